@@ -1,18 +1,22 @@
 use nom::{
     bits::complete::{tag, take},
-    combinator::map,
-    multi::many0,
+    combinator::{map, verify},
+    error::{Error, ErrorKind},
+    multi::{fold_many_m_n, many0},
     number::complete::be_u16,
     IResult,
 };
 
-type BitInput<'a> = (&'a [u8], usize); // a stream of bits grouped in bytes and the next bit to be read
+// A worked example of a real protocol: MIDI messages
+pub mod midi;
+
+pub type BitInput<'a> = (&'a [u8], usize); // a stream of bits grouped in bytes and the next bit to be read
 
 // Take 4 bits from the BitInput.
 // Store the output in a u8, because there's no u4 type, and u8
 // is the closest-available size.
 // 4 bits is called a "nibble" - it is half of a byte
-fn take_nibble(i: BitInput) -> IResult<BitInput, u8> {
+pub fn take_nibble(i: BitInput) -> IResult<BitInput, u8> {
     // Rust doesn't have a u4 type. How do we store a 4 bit number?
     // Nom's `take` parser solves this by padding your n bits with leading zeroes,
     // and storing them in some uint type like u8, u16 or whichever one you choose.
@@ -39,33 +43,237 @@ fn take_nibble(i: BitInput) -> IResult<BitInput, u8> {
 // This is just a simple wrapper around the `tag` parser, but it makes the
 // parameter types concrete instead of generic, so now Rust knows how to actually
 // store the pattern
-fn parser(pattern: u8, count: u8, input: BitInput) -> IResult<BitInput, u8> {
+pub fn parser(pattern: u8, count: u8, input: BitInput) -> IResult<BitInput, u8> {
     tag(pattern, count)(input)
 }
 
+// `take` and `tag` both consume `count` bits, the difference is in what they accept:
+// `take` returns whatever bits are there, while `tag` fails unless the bits are
+// exactly `pattern`. On success `tag` hands back the bits it matched (which are
+// `pattern` itself), so this parser both checks for a marker and captures it.
+pub fn tag_and_capture(pattern: u8, count: u8) -> impl Fn(BitInput) -> IResult<BitInput, u8> {
+    move |i| tag(pattern, count)(i)
+}
+
 // Takes one bit from the input, returning true for 1 and false for 0.
-fn take_bit(i: BitInput) -> IResult<BitInput, bool> {
+pub fn take_bit(i: BitInput) -> IResult<BitInput, bool> {
     map(take(1usize), |bits: u8| bits > 0)(i)
 }
 
+// Fields don't have to line up with byte boundaries. `take` keeps pulling bits
+// from the next byte until it has `count` of them, most significant bit first,
+// so a 12 bit field starting half way through a byte is read correctly:
+// [0000_1111, 1111_0000] from bit offset 4 gives 1111_1111_0000.
+// Like `take_dyn`, which it's built on, more than 64 bits is an error.
+pub fn take_straddling(count: usize) -> impl Fn(BitInput) -> IResult<BitInput, u64> {
+    take_dyn(count)
+}
+
+// For fields whose width is only known at runtime, e.g. read from an earlier field.
+// `take` itself doesn't care where `count` comes from, but if it's more than 64
+// the extra bits silently fall off the top of the u64. A width read from the input
+// can be anything, so this fails instead.
+pub fn take_dyn(count: usize) -> impl Fn(BitInput) -> IResult<BitInput, u64> {
+    move |i| {
+        if count > 64 {
+            return Err(nom::Err::Error(Error::new(i, ErrorKind::TooLarge)));
+        }
+        take(count)(i)
+    }
+}
+
+// Reads `count` bits of Gray code (where consecutive values differ by a single bit,
+// as used by rotary encoders) and decodes them into a plain binary number.
+// Each binary bit is the XOR of all the Gray bits above and including it.
+pub fn take_gray(count: usize) -> impl Fn(BitInput) -> IResult<BitInput, u64> {
+    move |i| {
+        map(take(count), |gray: u64| {
+            let mut value = gray;
+            let mut shifted = gray >> 1;
+            while shifted != 0 {
+                value ^= shifted;
+                shifted >>= 1;
+            }
+            value
+        })(i)
+    }
+}
+
+// `take` can only fill an integer type, so at most 64 bits (u64) at a time.
+// For wider fields (e.g. a 128 bit value or a large array of flags) take the
+// bits one by one instead.
+pub fn take_wide(count: usize) -> impl Fn(BitInput) -> IResult<BitInput, Vec<bool>> {
+    move |i| nom::multi::count(take_bit, count)(i)
+}
+
+// Consume every bit that's left, e.g. "the rest of the payload". If the input
+// starts part way into a byte, only the remaining bits of that byte are taken.
+pub fn take_rest(i: BitInput) -> IResult<BitInput, Vec<bool>> {
+    let (bytes, offset) = i;
+    take_wide(bytes.len() * 8 - offset)(i)
+}
+
+// The inverse of `take_wide`/`take_rest`: pack bits back into bytes, most
+// significant bit first. If the bits don't fill the last byte, it's padded with
+// zeros on the right.
+pub fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (n, &bit)| byte | (u8::from(bit) << (7 - n)))
+        })
+        .collect()
+}
+
+// Some formats say how long a field is before the field itself: a `len_bits` wide
+// count N, then N bits of data. The data bits are returned one by one, so N can be
+// larger than any integer type.
+pub fn take_length_prefixed(len_bits: usize) -> impl Fn(BitInput) -> IResult<BitInput, Vec<bool>> {
+    move |i| {
+        let (i, len): (BitInput, usize) = take(len_bits)(i)?;
+        take_wide(len)(i)
+    }
+}
+
+// Run-length encoding: a `run_count_bits` wide count N, then a `run_value_bits`
+// wide value that's repeated N times, e.g. a run of pixels all the same colour.
+// N comes straight from the input, so a run longer than `max_run` fails rather
+// than allocating whatever a bad count asks for.
+pub fn take_rle(
+    run_count_bits: usize,
+    run_value_bits: usize,
+    max_run: usize,
+) -> impl Fn(BitInput) -> IResult<BitInput, Vec<u64>> {
+    move |i| {
+        let (i, run_count): (BitInput, usize) = take(run_count_bits)(i)?;
+        if run_count > max_run {
+            return Err(nom::Err::Error(Error::new(i, ErrorKind::TooLarge)));
+        }
+        let (i, value): (BitInput, u64) = take(run_value_bits)(i)?;
+        Ok((i, vec![value; run_count]))
+    }
+}
+
+// Binary-coded decimal stores one decimal digit per nibble, so 0x42 means 42.
+// Reads `digits` nibbles and builds up the decimal number, failing if a nibble
+// isn't a decimal digit (0xA to 0xF). 20 or more digits can overflow a u64, so
+// `digits` over 19 fails straight away.
+pub fn take_bcd(digits: usize) -> impl Fn(BitInput) -> IResult<BitInput, u64> {
+    move |i| {
+        if digits > 19 {
+            return Err(nom::Err::Error(Error::new(i, ErrorKind::TooLarge)));
+        }
+        let decimal_digit = verify(take_nibble, |digit: &u8| *digit <= 9);
+        fold_many_m_n(
+            digits,
+            digits,
+            decimal_digit,
+            || 0,
+            |n, digit| n * 10 + u64::from(digit),
+        )(i)
+    }
+}
+
+// Checksums for verifying framed data once it's been parsed. These are the plain
+// bit-at-a-time versions, no lookup tables.
+
+// CRC-16/CCITT-FALSE: polynomial 0x1021, initial value 0xFFFF, most significant bit first
+pub fn crc16_ccitt(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in bytes {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// CRC-32 as used by Ethernet, zip and PNG: reflected polynomial 0xEDB88320,
+// initial value and final XOR 0xFFFFFFFF
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for &byte in bytes {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// nom's bit parsers need the whole input up front as a slice. When bytes arrive a
+// few at a time (a socket, a decompressor...), this reads fields from any iterator
+// of bytes instead, only pulling the next byte once the current one is used up.
+pub struct BitStreamReader<I: Iterator<Item = u8>> {
+    bytes: I,
+    // The byte being read, and how many of its bits (from the right) are still unread
+    current: u8,
+    bits_left: u8,
+}
+
+impl<I: Iterator<Item = u8>> BitStreamReader<I> {
+    pub fn new(bytes: I) -> Self {
+        BitStreamReader {
+            bytes,
+            current: 0,
+            bits_left: 0,
+        }
+    }
+
+    // Read the next `count` bits, most significant bit first, like `take`.
+    // None if `count` is over 64, or if the bytes run out part way through (the
+    // bits read so far are lost).
+    pub fn read_bits(&mut self, count: usize) -> Option<u64> {
+        if count > 64 {
+            return None;
+        }
+        let mut value = 0;
+        for _ in 0..count {
+            if self.bits_left == 0 {
+                self.current = self.bytes.next()?;
+                self.bits_left = 8;
+            }
+            self.bits_left -= 1;
+            let bit = (self.current >> self.bits_left) & 1;
+            value = (value << 1) | u64::from(bit);
+        }
+        Some(value)
+    }
+}
+
 // Converting byte-streams to bit-streams and back
 
 // Stub example type. Imagine this has to be parsed from individual bits.
+#[allow(dead_code)]
 struct BitwiseHeader;
 
 // A bit-level parser
-fn parse_header(i: BitInput) -> IResult<BitInput, BitwiseHeader> {
+#[allow(dead_code)]
+fn parse_header(_i: BitInput) -> IResult<BitInput, BitwiseHeader> {
     todo!()
 }
 
 // Stub example type.
 // The header has to be parsed from bits, but the body can be parsed from bytes.
+#[allow(dead_code)]
 struct Message {
     header: BitwiseHeader,
     body: Vec<u16>,
 }
 
 // A byte-level parser that calls a bit-level parser
+#[allow(dead_code)]
 fn parse_msg(i: &[u8]) -> IResult<&[u8], Message> {
     // The header has to be parsed from bits
     let (i, header) = nom::bits::bits(parse_header)(i)?;
@@ -101,6 +309,172 @@ mod tests {
         assert!(parser(0b1111_1110, 8, (&[0b1111_1111], 0)).is_err());
     }
 
+    #[test]
+    fn test_take_straddling() {
+        let bytes = [0b0000_1111, 0b1111_0000];
+        // 12 bits starting at bit 4 take the low nibble of the first byte and all
+        // of the second byte
+        let (input, value) = take_straddling(12)((&bytes, 4)).unwrap();
+        assert_eq!(value, 0b1111_1111_0000);
+        assert_eq!(input, (&bytes[2..], 0));
+        // From the start, 12 bits end half way through the second byte
+        let (input, value) = take_straddling(12)((&bytes, 0)).unwrap();
+        assert_eq!(value, 0b0000_1111_1111);
+        assert_eq!(input, (&bytes[1..], 4));
+        // And a field wider than a byte can straddle two boundaries
+        let bytes = [0b0000_0001, 0b1111_1111, 0b1000_0000];
+        let (_input, value) = take_straddling(11)((&bytes, 7)).unwrap();
+        assert_eq!(value, 0b111_1111_1110);
+        // But not past the width of a u64
+        let bytes = [0xff; 10];
+        assert!(take_straddling(65)((&bytes, 4)).is_err());
+    }
+
+    #[test]
+    fn test_take_dyn() {
+        // A 3 bit width of 5, then a 5 bit field
+        let bytes = [0b1011_0110];
+        let (input, width) = take_dyn(3)((&bytes, 0)).unwrap();
+        assert_eq!(width, 5);
+        let (input, value) = take_dyn(width as usize)(input).unwrap();
+        assert_eq!(value, 0b1_0110);
+        assert_eq!(input, (&bytes[1..], 0));
+        // Too wide for a u64
+        let bytes = [0xff; 9];
+        assert!(take_dyn(65)((&bytes, 0)).is_err());
+    }
+
+    #[test]
+    fn test_take_gray() {
+        let (input, value) = take_gray(4)(([0b0010_0000].as_ref(), 0)).unwrap();
+        assert_eq!(value, 3);
+        assert_eq!(input, ([0b0010_0000].as_ref(), 4));
+
+        // The 3 bit Gray sequence counts 0 to 7
+        let sequence = [0b000, 0b001, 0b011, 0b010, 0b110, 0b111, 0b101, 0b100];
+        for (expected, gray) in sequence.iter().enumerate() {
+            let byte = [gray << 5];
+            let (_input, value) = take_gray(3)((&byte, 0)).unwrap();
+            assert_eq!(value, expected as u64);
+        }
+    }
+
+    #[test]
+    fn test_take_wide() {
+        // 9 bytes, take 70 bits
+        let bytes = [0xFF, 0, 0, 0, 0, 0, 0, 0b0000_0001, 0b1010_0000];
+        let (input, bits) = take_wide(70)((&bytes, 0)).unwrap();
+        assert_eq!(bits.len(), 70);
+        assert!(bits[..8].iter().all(|&b| b));
+        assert!(bits[8..63].iter().all(|&b| !b));
+        assert_eq!(bits[63..], [true, true, false, true, false, false, false]);
+        assert_eq!(input, (&bytes[8..], 6));
+    }
+
+    #[test]
+    fn test_take_rest() {
+        // One and a half bytes left
+        let input = ([0b1010_1100, 0b0011_1111].as_ref(), 4);
+        let (input, bits) = take_rest(input).unwrap();
+        assert_eq!(
+            bits,
+            [1, 1, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1]
+                .map(|b| b == 1)
+                .to_vec()
+        );
+        assert_eq!(input.0.len(), 0);
+        // Nothing left to take
+        assert_eq!(take_rest(input).unwrap().1, vec![]);
+    }
+
+    #[test]
+    fn test_pack_bits() {
+        let bits = [1, 0, 1, 1, 0, 0, 1, 1, 1, 1].map(|b| b == 1);
+        assert_eq!(pack_bits(&bits), vec![0b1011_0011, 0b1100_0000]);
+        assert_eq!(pack_bits(&[]), vec![]);
+        // Round trips through take_wide when it's a whole number of bytes
+        let bytes = [0xde, 0xad];
+        let (_input, bits) = take_wide(16)((bytes.as_ref(), 0)).unwrap();
+        assert_eq!(pack_bits(&bits), bytes);
+    }
+
+    #[test]
+    fn test_take_length_prefixed() {
+        // A length of 5, then the 5 data bits 10110, then one bit left over
+        let input = ([0b0101_1011, 0b0100_0000].as_ref(), 0);
+        let (input, bits) = take_length_prefixed(4)(input).unwrap();
+        assert_eq!(bits, vec![true, false, true, true, false]);
+        assert_eq!(input, ([0b0100_0000].as_ref(), 1));
+        // A length of 15 runs past the end of the input
+        assert!(take_length_prefixed(4)(([0b1111_0000].as_ref(), 0)).is_err());
+    }
+
+    #[test]
+    fn test_take_rle() {
+        // A run of 5, then the value 3, then a byte left over
+        let input = ([0b0101_0011, 0b1100_0000].as_ref(), 0);
+        let (input, values) = take_rle(4, 4, 15)(input).unwrap();
+        assert_eq!(values, vec![3, 3, 3, 3, 3]);
+        assert_eq!(input, ([0b1100_0000].as_ref(), 0));
+        // A run of 0 is just an empty run
+        let (_input, values) = take_rle(4, 4, 15)(([0b0000_0111].as_ref(), 0)).unwrap();
+        assert!(values.is_empty());
+        // A run of 5 is over the limit of 4
+        assert!(take_rle(4, 4, 4)(([0b0101_0011].as_ref(), 0)).is_err());
+        // A 64 bit count of u64::MAX is rejected without trying to allocate it
+        let bytes = [0xff; 9];
+        assert!(take_rle(64, 8, 1 << 20)((bytes.as_ref(), 0)).is_err());
+    }
+
+    #[test]
+    fn test_take_bcd() {
+        let (_input, value) = take_bcd(2)(([0x42].as_ref(), 0)).unwrap();
+        assert_eq!(value, 42);
+        let (input, value) = take_bcd(3)(([0x19, 0x87].as_ref(), 0)).unwrap();
+        assert_eq!(value, 198);
+        assert_eq!(input, ([0x87].as_ref(), 4));
+        assert!(take_bcd(2)(([0x4A].as_ref(), 0)).is_err());
+        // 19 nines is the most digits that always fit in a u64
+        let nines = [0x99; 10];
+        let (_input, value) = take_bcd(19)((nines.as_ref(), 0)).unwrap();
+        assert_eq!(value, 9_999_999_999_999_999_999);
+        assert!(take_bcd(20)((nines.as_ref(), 0)).is_err());
+    }
+
+    #[test]
+    fn test_bit_stream_reader() {
+        let bytes = vec![0b1010_1100, 0b0011_1111, 0xab, 0xcd];
+        let mut reader = BitStreamReader::new(bytes.into_iter());
+        assert_eq!(reader.read_bits(4), Some(0b1010));
+        assert_eq!(reader.read_bits(1), Some(1));
+        // Straddles the first two bytes
+        assert_eq!(reader.read_bits(7), Some(0b100_0011));
+        assert_eq!(reader.read_bits(4), Some(0b1111));
+        assert_eq!(reader.read_bits(16), Some(0xabcd));
+        assert_eq!(reader.read_bits(0), Some(0));
+        assert_eq!(reader.read_bits(1), None);
+        assert_eq!(reader.read_bits(65), None);
+    }
+
+    #[test]
+    fn test_crc() {
+        // The standard check values for each algorithm
+        assert_eq!(crc16_ccitt(b"123456789"), 0x29B1);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc16_ccitt(b""), 0xFFFF);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_tag_and_capture() {
+        let input = ([0b1011_0000].as_ref(), 0);
+        let (rest, value) = tag_and_capture(0b101, 3)(input).unwrap();
+        assert_eq!(value, 0b101);
+        assert_eq!(rest, ([0b1011_0000].as_ref(), 3));
+        // take would happily read 101 here, but tag insists on 111
+        assert!(tag_and_capture(0b111, 3)(input).is_err());
+    }
+
     #[test]
     fn test_take_bit() {
         let input = ([0b10101010].as_ref(), 0);
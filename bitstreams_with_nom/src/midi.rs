@@ -0,0 +1,140 @@
+// A real protocol built from the bit primitives: MIDI channel messages.
+// Every MIDI byte starts with a flag bit. In a status byte it's 1, and the other
+// 7 bits are the kind of message (3 bits) and the channel it's for (4 bits).
+// In the data bytes that follow it's 0, leaving 7 bits for the value.
+// See https://www.midi.org/specifications-old/item/table-1-summary-of-midi-message
+use crate::{take_bit, BitInput};
+use nom::{
+    bits::complete::take,
+    combinator::verify,
+    error::{Error, ErrorKind},
+    sequence::preceded,
+    IResult,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message {
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    // How far the pitch wheel is from the centre, from -8192 to 8191
+    PitchBend { channel: u8, value: i16 },
+}
+
+// The flag bit is 1, then the message kind and channel
+fn status(i: BitInput) -> IResult<BitInput, (u8, u8)> {
+    let (i, _) = verify(take_bit, |&is_status| is_status)(i)?;
+    let (i, kind) = take(3u8)(i)?;
+    let (i, channel) = take(4u8)(i)?;
+    Ok((i, (kind, channel)))
+}
+
+// The flag bit is 0, then a 7 bit value
+fn data(i: BitInput) -> IResult<BitInput, u8> {
+    preceded(verify(take_bit, |&is_status| !is_status), take(7u8))(i)
+}
+
+impl Message {
+    pub fn deserialize(input: BitInput) -> IResult<BitInput, Self> {
+        let (i, (kind, channel)) = status(input)?;
+        match kind {
+            0b000 | 0b001 => {
+                let (i, note) = data(i)?;
+                let (i, velocity) = data(i)?;
+                let message = if kind == 0b000 {
+                    Message::NoteOff {
+                        channel,
+                        note,
+                        velocity,
+                    }
+                } else {
+                    Message::NoteOn {
+                        channel,
+                        note,
+                        velocity,
+                    }
+                };
+                Ok((i, message))
+            }
+            0b110 => {
+                // A 14 bit unsigned value, least significant 7 bits first, where
+                // 8192 means the wheel is centred. Shift it so the centre is 0.
+                let (i, lsb) = data(i)?;
+                let (i, msb) = data(i)?;
+                let raw = (i16::from(msb) << 7) | i16::from(lsb);
+                Ok((
+                    i,
+                    Message::PitchBend {
+                        channel,
+                        value: raw - 8192,
+                    },
+                ))
+            }
+            // Other kinds of message aren't supported
+            _ => Err(nom::Err::Error(Error::new(input, ErrorKind::Switch))),
+        }
+    }
+
+    // Parse a message from bytes, handing back whatever bytes follow it
+    pub fn from_bytes(i: &[u8]) -> IResult<&[u8], Self> {
+        nom::bits::bits(Self::deserialize)(i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_note_on() {
+        // Note On, channel 3, middle C (60), velocity 100, then another byte
+        let bytes = [0x93, 60, 100, 0x83];
+        let (rest, message) = Message::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            message,
+            Message::NoteOn {
+                channel: 3,
+                note: 60,
+                velocity: 100
+            }
+        );
+        assert_eq!(rest, &[0x83]);
+        // A data byte can't have its top bit set
+        assert!(Message::from_bytes(&[0x93, 0xbc, 100]).is_err());
+        // and a message can't start with a data byte
+        assert!(Message::from_bytes(&[60, 100]).is_err());
+    }
+
+    #[test]
+    fn test_pitch_bend() {
+        let centred = Message::from_bytes(&[0xe0, 0x00, 0x40]).unwrap().1;
+        assert_eq!(
+            centred,
+            Message::PitchBend {
+                channel: 0,
+                value: 0
+            }
+        );
+        let lowest = Message::from_bytes(&[0xe5, 0x00, 0x00]).unwrap().1;
+        assert_eq!(
+            lowest,
+            Message::PitchBend {
+                channel: 5,
+                value: -8192
+            }
+        );
+        let highest = Message::from_bytes(&[0xef, 0x7f, 0x7f]).unwrap().1;
+        assert_eq!(
+            highest,
+            Message::PitchBend {
+                channel: 15,
+                value: 8191
+            }
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        // Control Change isn't one of the messages we know
+        assert!(Message::from_bytes(&[0xb0, 7, 100]).is_err());
+    }
+}
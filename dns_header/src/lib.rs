@@ -16,23 +16,35 @@
 +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
  */
 
+use std::collections::HashMap;
 use std::convert::TryFrom;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
-use nom::bits::complete::take;
-use nom::combinator::map_res;
+// Traces what the parsers are doing via the `log` crate, when built with the
+// `logging` feature. Without it, this expands to nothing.
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        #[cfg(feature = "logging")]
+        log::trace!($($arg)+);
+    };
+}
+
+use nom::bits::complete::{tag, take};
+use nom::combinator::{all_consuming, consumed, cut, map, map_res, success, verify};
+use nom::multi::{count, length_count, many_till};
 use nom::IResult;
 
 // All DNS messages start with a Header (both queries and responses!)
 // Structure is defined at https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.1
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Header {
     // A 16 bit identifier assigned by the program that generates any kind of
     // query. This identifier is copied in the corresponding reply and can be used
     // by the requester to match up replies to outstanding queries
-    pub id: u16,
+    pub id: QueryId,
     // A one bit field that specifies whether this message is a query (0), or a
     // response (1)
-    is_query: bool,
+    is_response: bool,
     // A four bit field that specifies kind of query in this message. This value
     // is set by the originator of a query and copied into the response.
     opcode: Opcode,
@@ -63,6 +75,25 @@ pub struct Header {
     pub additional_records_count: u16,
 }
 
+// The 16 bit message ID. Wrapped in its own type so it can't be mixed up with the
+// (also 16 bit) counts, and so matching a reply to its query reads clearly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueryId(pub u16);
+
+impl QueryId {
+    // Whether a reply with this ID answers a query with the `other` ID
+    pub fn matches(&self, other: &QueryId) -> bool {
+        self == other
+    }
+}
+
+// IDs are usually shown in hex, e.g. 0x1a2b
+impl std::fmt::Display for QueryId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#06x}", self.0)
+    }
+}
+
 type BitInput<'a> = (&'a [u8], usize);
 
 // Takes one bit from the BitInput.
@@ -75,14 +106,18 @@ pub fn take_bit(i: BitInput) -> IResult<BitInput, bool> {
 
 // A four bit field that specifies kind of query in this message
 // This value is set by the originator of a query and copied into the response.
-#[derive(Debug)]
-enum Opcode {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
     // 0: a standard query (QUERY)
     Query,
     // 1: an inverse query (IQUERY)
     InverseQuery,
     // 2: a server status request (STATUS)
     Status,
+    // Anything else. Only produced when parsing with `allow_unknown_codes` set in
+    // `ParseOptions` (as `Header::parse_lenient` does), otherwise unknown opcodes
+    // are rejected.
+    Unknown(u8),
 }
 
 impl TryFrom<u8> for Opcode {
@@ -99,6 +134,89 @@ impl TryFrom<u8> for Opcode {
     }
 }
 
+// A four bit field set as part of responses.
+// Values 6-15 are reserved for future use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+    // 0: No error condition
+    NoError,
+    // 1: The name server was unable to interpret the query.
+    FormatError,
+    // 2: The name server was unable to process this query due to a problem
+    // with the name server.
+    ServerFailure,
+    // 3: Meaningful only for responses from an authoritative name server, this
+    // code signifies that the domain name referenced in the query does not exist.
+    NameError,
+    // 4: The name server does not support the requested kind of query.
+    NotImplemented,
+    // 5: The name server refuses to perform the specified operation for policy
+    // reasons.
+    Refused,
+    // Anything else. Only produced when parsing with `allow_unknown_codes` set in
+    // `ParseOptions` (as `Header::parse_lenient` does), otherwise unknown response codes
+    // are rejected.
+    Unknown(u8),
+}
+
+impl TryFrom<u8> for ResponseCode {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let rcode = match value {
+            0 => Self::NoError,
+            1 => Self::FormatError,
+            2 => Self::ServerFailure,
+            3 => Self::NameError,
+            4 => Self::NotImplemented,
+            5 => Self::Refused,
+            other => anyhow::bail!("Unknown response code {other}"),
+        };
+        Ok(rcode)
+    }
+}
+
+// How the IANA DNS RCODE registry treats a response code value.
+// With EDNS the rcode is extended to 12 bits (and TSIG/TKEY carry 16 bit error
+// codes), so this covers far more than the variants of `ResponseCode`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RcodeClass {
+    Assigned,
+    Reserved,
+    Unassigned,
+}
+
+impl ResponseCode {
+    // Classify any rcode value, known to `ResponseCode` or not.
+    // See https://www.iana.org/assignments/dns-parameters/dns-parameters.xhtml#dns-parameters-6
+    // and https://datatracker.ietf.org/doc/html/rfc6895#section-2.3
+    pub fn classify(value: u16) -> RcodeClass {
+        match value {
+            // NoError through DSOTYPENI
+            0..=11 => RcodeClass::Assigned,
+            // BADVERS/BADSIG through BADCOOKIE
+            16..=23 => RcodeClass::Assigned,
+            // Private use, and the last value which needs a Standards Action
+            3841..=4095 | 65535 => RcodeClass::Reserved,
+            _ => RcodeClass::Unassigned,
+        }
+    }
+
+    // An explanation of the code for end users, e.g. for a CLI to print instead of
+    // a bare NXDOMAIN
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            Self::NoError => "The query completed successfully",
+            Self::FormatError => "The server could not understand the query",
+            Self::ServerFailure => "The server failed to process the query",
+            Self::NameError => "The domain name does not exist",
+            Self::NotImplemented => "The server does not support this kind of query",
+            Self::Refused => "The server refused to answer the query",
+            Self::Unknown(_) => "The server sent an unrecognised response code",
+        }
+    }
+}
+
 // We also need to parse 4-bit numbers from bit-streams:
 // A "nibble" is half a byte, i.e. 4-bit number.
 pub fn take_nibble(i: BitInput) -> IResult<BitInput, u8> {
@@ -110,6 +228,14 @@ pub fn take_nibble(i: BitInput) -> IResult<BitInput, u8> {
 // let (i, opcode) = map_res(take_nibble, Opcode::try_from)(i)?; // map_res applies a function
 // which return a Result, over the result of a parser.
 
+// The same idiom works for any small enum packed into a bit field, so wrap it up:
+// take `count` bits and try to convert them into a `T`.
+pub fn take_enum<'a, T: TryFrom<u8>>(
+    count: usize,
+) -> impl FnMut(BitInput<'a>) -> IResult<BitInput<'a>, T> {
+    map_res(take(count), T::try_from)
+}
+
 // Once you know the size of each field, and you have a struct to represent them all, it's actually
 // pretty easy to parse the protocol.
 
@@ -118,40 +244,1400 @@ pub fn take_u16(i: BitInput) -> IResult<BitInput, u16> {
     take(16u8)(i)
 }
 
-impl Header {
+// Same for 32 bits. DNS is big-endian (network order) throughout, e.g. for a record's TTL.
+pub fn take_u32(i: BitInput) -> IResult<BitInput, u32> {
+    take(32u8)(i)
+}
+
+// Little-endian variants for protocols that put the least significant byte first.
+// They read the same bits as above, we just swap the bytes around afterwards.
+pub fn take_u16_le(i: BitInput) -> IResult<BitInput, u16> {
+    map(take_u16, u16::swap_bytes)(i)
+}
+
+pub fn take_u32_le(i: BitInput) -> IResult<BitInput, u32> {
+    map(take_u32, u32::swap_bytes)(i)
+}
+
+// Something unexpected that `Header::parse_lenient` let through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warning {
+    UnknownOpcode(u8),
+    UnknownResponseCode(u8),
+    // The reserved Z bits, which should all be 0
+    NonzeroZ(u8),
+}
+
+// The 16 bits of the header between the ID and the counts: QR, Opcode, AA, TC,
+// RD, RA, Z and RCODE. See the Header fields for what each one means.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderFlags {
+    // The QR bit, set for responses
+    pub is_response: bool,
+    pub opcode: Opcode,
+    pub authoritative_answer: bool,
+    pub truncation: bool,
+    pub recursion_desired: bool,
+    pub recursion_available: bool,
+    pub resp_code: ResponseCode,
+}
+
+// The single bit flags of the flags word, bitflags style, for building the word
+// by hand when constructing a packet. The opcode, Z and rcode bits are left out:
+// they're numbers rather than flags, and are dropped by `from_word`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderFlagBits(u16);
+
+impl HeaderFlagBits {
+    pub const QR: Self = HeaderFlagBits(1 << 15);
+    pub const AA: Self = HeaderFlagBits(1 << 10);
+    pub const TC: Self = HeaderFlagBits(1 << 9);
+    pub const RD: Self = HeaderFlagBits(1 << 8);
+    pub const RA: Self = HeaderFlagBits(1 << 7);
+    const ALL: u16 = Self::QR.0 | Self::AA.0 | Self::TC.0 | Self::RD.0 | Self::RA.0;
+
+    // Pick the flags out of a whole flags word
+    pub fn from_word(word: u16) -> Self {
+        HeaderFlagBits(word & Self::ALL)
+    }
+
+    // The flags in their positions in the flags word, with everything else 0
+    pub fn to_word(self) -> u16 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for HeaderFlagBits {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        HeaderFlagBits(self.0 | other.0)
+    }
+}
+
+// The choices the header parsers make about input that's not quite right, in one
+// place. The default is what `Header::deserialize` does: reject unknown codes and
+// nonzero Z bits, but don't look at the counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseOptions {
+    // Keep unknown opcodes and response codes as their `Unknown` variants
+    // instead of failing the parse
+    pub allow_unknown_codes: bool,
+    // Accept nonzero Z bits instead of failing the parse
+    pub allow_nonzero_z: bool,
+    // Reject headers whose counts don't make sense, see `Header::deserialize_strict`
+    pub check_counts: bool,
+}
+
+impl ParseOptions {
+    // Reject anything questionable
+    pub fn strict() -> Self {
+        ParseOptions {
+            allow_unknown_codes: false,
+            allow_nonzero_z: false,
+            check_counts: true,
+        }
+    }
+
+    // Accept anything that can be parsed at all
+    pub fn lenient() -> Self {
+        ParseOptions {
+            allow_unknown_codes: true,
+            allow_nonzero_z: true,
+            check_counts: false,
+        }
+    }
+}
+
+impl HeaderFlags {
     pub fn deserialize(i: BitInput) -> IResult<BitInput, Self> {
-        let (i, id) = take_u16(i)?;
+        map(
+            |i| Self::deserialize_with(ParseOptions::default(), i),
+            |(flags, _z)| flags,
+        )(i)
+    }
+
+    // The Z bits are returned alongside the flags, for `deserialize_capture_z`
+    fn deserialize_with(opts: ParseOptions, i: BitInput) -> IResult<BitInput, (Self, u8)> {
         let (i, qr) = take_bit(i)?;
-        let (i, opcode) = map_res(take_nibble, Opcode::try_from)(i)?;
+        let (i, opcode) = if opts.allow_unknown_codes {
+            map(take_nibble, |n| {
+                Opcode::try_from(n).unwrap_or(Opcode::Unknown(n))
+            })(i)?
+        } else {
+            take_enum(4)(i)?
+        };
         let (i, aa) = take_bit(i)?;
         let (i, tc) = take_bit(i)?;
         let (i, rd) = take_bit(i)?;
-        let (mut i, ra) = take_bit(i)?;
+        let (i, ra) = take_bit(i)?;
         // The spec defines the Z field as three consecutive 0s.
-        for _ in 0..3 {
-            let z;
-            (i, z) = take_bit(i)?;
-            assert!(!z);
+        let (i, z) = verify(take(3u8), |&z: &u8| opts.allow_nonzero_z || z == 0)(i)?;
+        let (i, rcode) = if opts.allow_unknown_codes {
+            map(take_nibble, |n| {
+                ResponseCode::try_from(n).unwrap_or(ResponseCode::Unknown(n))
+            })(i)?
+        } else {
+            take_enum(4)(i)?
+        };
+        let flags = HeaderFlags {
+            is_response: qr,
+            opcode,
+            authoritative_answer: aa,
+            truncation: tc,
+            recursion_desired: rd,
+            recursion_available: ra,
+            resp_code: rcode,
+        };
+        Ok((i, (flags, z)))
+    }
+}
+
+impl Header {
+    pub fn deserialize(i: BitInput) -> IResult<BitInput, Self> {
+        Self::deserialize_with(ParseOptions::default(), i)
+    }
+
+    // Parse a header, making the choices in `opts` about anything questionable.
+    // Like `deserialize_strict`, headers that fail the count check are Failures.
+    pub fn deserialize_with(opts: ParseOptions, i: BitInput) -> IResult<BitInput, Self> {
+        let counts_are_sane = |header: &Header| header.is_response || header.answer_count == 0;
+        let (rest, (header, _z)) = Self::deserialize_parts(opts, i)?;
+        if !opts.check_counts {
+            return Ok((rest, header));
         }
-        let (i, rcode) = map_res(take_nibble, ResponseCode::try_from)(i)?; // ResponseCode unimplemented here
+        // Checked against the input the header started at, so the Failure points
+        // there rather than at whatever follows the header
+        let (_, header) = cut(verify(success(header), counts_are_sane))(i)?;
+        Ok((rest, header))
+    }
+
+    // The header along with its Z bits
+    fn deserialize_parts(opts: ParseOptions, i: BitInput) -> IResult<BitInput, (Self, u8)> {
+        let (i, id) = map(take_u16, QueryId)(i)?;
+        let (i, (flags, z)) = HeaderFlags::deserialize_with(opts, i)?;
+        trace!("Parsed flags of message {id}: {flags:?}");
         let (i, qdcount) = take_u16(i)?;
         let (i, ancount) = take_u16(i)?;
         let (i, nscount) = take_u16(i)?;
         let (i, arcount) = take_u16(i)?;
+        trace!(
+            "Parsed header of message {id}: {qdcount} questions, {ancount} answers, \
+             {nscount} authority records, {arcount} additional records"
+        );
         let header = Header {
             id,
-            is_query: qr,
-            opcode,
-            authoritative_answer: aa,
-            truncation: tc,
-            recursion_desired: rd,
-            recursion_available: ra,
-            resp_code: rcode,
+            is_response: flags.is_response,
+            opcode: flags.opcode,
+            authoritative_answer: flags.authoritative_answer,
+            truncation: flags.truncation,
+            recursion_desired: flags.recursion_desired,
+            recursion_available: flags.recursion_available,
+            resp_code: flags.resp_code,
             question_count: qdcount,
             answer_count: ancount,
             name_server_count: nscount,
             additional_records_count: arcount,
         };
-        Ok((i, header))
+        Ok((i, (header, z)))
+    }
+
+    // Like `deserialize`, but also rejects headers whose counts don't make sense.
+    // For now that's a query (QR=0) that claims to carry answers.
+    // Errors are turned into Failures with `cut`, so an `alt` won't go on to try
+    // other parsers on a header that's known to be bad.
+    pub fn deserialize_strict(i: BitInput) -> IResult<BitInput, Self> {
+        cut(|i| Self::deserialize_with(ParseOptions::strict(), i))(i)
+    }
+
+    // For tools that would rather see a strange header than no header at all:
+    // parses with `ParseOptions::lenient()`, so unknown opcodes and response codes
+    // become `Unknown` variants and nonzero Z bits are accepted, and each of those
+    // is reported as a warning alongside the header instead of failing the parse.
+    pub fn parse_lenient(i: BitInput) -> IResult<BitInput, (Self, Vec<Warning>)> {
+        let (i, (header, z)) = Self::deserialize_parts(ParseOptions::lenient(), i)?;
+        let mut warnings = Vec::new();
+        if let Opcode::Unknown(n) = header.opcode {
+            warnings.push(Warning::UnknownOpcode(n));
+        }
+        if let ResponseCode::Unknown(n) = header.resp_code {
+            warnings.push(Warning::UnknownResponseCode(n));
+        }
+        if z != 0 {
+            warnings.push(Warning::NonzeroZ(z));
+        }
+        Ok((i, (header, warnings)))
+    }
+
+    // Like `deserialize`, but instead of insisting the reserved Z bits are 0,
+    // return them alongside the header, e.g. to spot non-compliant senders.
+    pub fn deserialize_capture_z(i: BitInput) -> IResult<BitInput, (Self, u8)> {
+        let opts = ParseOptions {
+            allow_nonzero_z: true,
+            ..ParseOptions::default()
+        };
+        Self::deserialize_parts(opts, i)
+    }
+
+    // Parse into an existing header instead of returning a new one, e.g. to reuse
+    // a single `Header` across a hot loop. `out` is only touched if parsing succeeds.
+    pub fn deserialize_into<'a>(i: BitInput<'a>, out: &mut Header) -> IResult<BitInput<'a>, ()> {
+        let (i, header) = Self::deserialize(i)?;
+        *out = header;
+        Ok((i, ()))
+    }
+
+    // Start the header of a response to `query`, the way a server would: copy the
+    // id, opcode, question count and RD flag, set QR, and report no error.
+    // The answer, authority and additional counts start at zero, and RA is left
+    // cleared.
+    pub fn new_response(query: &Header) -> Header {
+        Header {
+            id: query.id,
+            is_response: true,
+            opcode: query.opcode,
+            authoritative_answer: false,
+            truncation: false,
+            recursion_desired: query.recursion_desired,
+            recursion_available: false,
+            resp_code: ResponseCode::NoError,
+            question_count: query.question_count,
+            answer_count: 0,
+            name_server_count: 0,
+            additional_records_count: 0,
+        }
+    }
+
+    // Decode a flags word that has already been pulled out of a header, e.g. by
+    // another parser, using the same bit parser as `deserialize`.
+    pub fn decode_flags(flags: u16) -> anyhow::Result<HeaderFlags> {
+        let bytes = flags.to_be_bytes();
+        let (_, decoded) = HeaderFlags::deserialize((&bytes, 0))
+            .map_err(|e| anyhow::anyhow!("Invalid flags {flags:#06x}: {:?}", e.map(|e| e.code)))?;
+        Ok(decoded)
+    }
+
+    // The byte-level entry point: switch to bits just for the header, and hand back
+    // the remaining bytes (the rest of the message).
+    pub fn from_bytes(i: &[u8]) -> IResult<&[u8], Self> {
+        nom::bits::bits(Self::deserialize)(i)
+    }
+
+    // For buffers that should hold nothing but a header: anything after the 12
+    // header bytes is an error, as it probably means the framing is off.
+    pub fn parse_exact(bytes: &[u8]) -> anyhow::Result<Header> {
+        let (_, header) = all_consuming(Self::from_bytes)(bytes)
+            .map_err(|e| anyhow::anyhow!("Invalid header: {:?}", e.map(|e| e.code)))?;
+        Ok(header)
+    }
+
+    // Parse a header from the start of a packet, also returning the two raw bytes
+    // the flags were decoded from, for showing "raw vs decoded" side by side.
+    pub fn parse_with_raw(bytes: &[u8]) -> anyhow::Result<(Header, [u8; 2])> {
+        let header = Header::try_from(bytes)?;
+        // The flags come right after the 2 byte ID. The parse succeeded, so the
+        // bytes are there.
+        let raw_flags = [bytes[2], bytes[3]];
+        Ok((header, raw_flags))
+    }
+
+    // All the flags in one value, e.g. to compare the flags of two messages at once
+    pub fn flags(&self) -> HeaderFlags {
+        HeaderFlags {
+            is_response: self.is_response,
+            opcode: self.opcode,
+            authoritative_answer: self.authoritative_answer,
+            truncation: self.truncation,
+            recursion_desired: self.recursion_desired,
+            recursion_available: self.recursion_available,
+            resp_code: self.resp_code,
+        }
+    }
+
+    // Whether the headers match in everything but their IDs, which are random, e.g.
+    // to compare a parsed header against a fixture
+    pub fn eq_ignoring_id(&self, other: &Header) -> bool {
+        self.flags() == other.flags()
+            && self.question_count == other.question_count
+            && self.answer_count == other.answer_count
+            && self.name_server_count == other.name_server_count
+            && self.additional_records_count == other.additional_records_count
+    }
+
+    // Describe every field that differs between the two headers, e.g.
+    // "qr: false -> true" or "ancount: 0 -> 2", in wire order. Handy for checking
+    // what a resolver changed between a query and its response.
+    pub fn diff(&self, other: &Header) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |name: &str, before: String, after: String| {
+            if before != after {
+                changes.push(format!("{name}: {before} -> {after}"));
+            }
+        };
+        compare("id", self.id.to_string(), other.id.to_string());
+        let (a, b) = (self.flags(), other.flags());
+        compare("qr", a.is_response.to_string(), b.is_response.to_string());
+        compare(
+            "opcode",
+            format!("{:?}", a.opcode),
+            format!("{:?}", b.opcode),
+        );
+        compare(
+            "aa",
+            a.authoritative_answer.to_string(),
+            b.authoritative_answer.to_string(),
+        );
+        compare("tc", a.truncation.to_string(), b.truncation.to_string());
+        compare(
+            "rd",
+            a.recursion_desired.to_string(),
+            b.recursion_desired.to_string(),
+        );
+        compare(
+            "ra",
+            a.recursion_available.to_string(),
+            b.recursion_available.to_string(),
+        );
+        compare(
+            "rcode",
+            format!("{:?}", a.resp_code),
+            format!("{:?}", b.resp_code),
+        );
+        compare(
+            "qdcount",
+            self.question_count.to_string(),
+            other.question_count.to_string(),
+        );
+        compare(
+            "ancount",
+            self.answer_count.to_string(),
+            other.answer_count.to_string(),
+        );
+        compare(
+            "nscount",
+            self.name_server_count.to_string(),
+            other.name_server_count.to_string(),
+        );
+        compare(
+            "arcount",
+            self.additional_records_count.to_string(),
+            other.additional_records_count.to_string(),
+        );
+        changes
+    }
+
+    // Whether the RD flag is set, i.e. the client asked for a recursive lookup.
+    pub fn wants_recursion(&self) -> bool {
+        self.recursion_desired
+    }
+
+    // Whether the RA flag is set, i.e. the server supports recursive lookups.
+    pub fn offers_recursion(&self) -> bool {
+        self.recursion_available
+    }
+}
+
+// Parse a header from the first 12 bytes of a packet, e.g. `Header::try_from(&bytes[..])`.
+// Anything after the header is ignored.
+impl TryFrom<&[u8]> for Header {
+    type Error = anyhow::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let (_, header) = Header::from_bytes(bytes)
+            .map_err(|e| anyhow::anyhow!("Invalid header: {:?}", e.map(|e| e.code)))?;
+        Ok(header)
+    }
+}
+
+// True if the query asked for recursion but the response says the server doesn't
+// provide it. A common cause of surprising answers from non-recursive servers.
+pub fn recursion_mismatch(query: &Header, response: &Header) -> bool {
+    query.wants_recursion() && !response.offers_recursion()
+}
+
+// A saved position in a bit stream. nom parsers take their input by value and
+// never modify it, so "rewinding" is just a matter of keeping a copy of the input
+// from before the attempt, which is all this does. It makes the backtracking
+// explicit when you want to try something else after a failed parse without `alt`.
+#[derive(Debug, Clone, Copy)]
+pub struct Checkpoint<'a> {
+    input: BitInput<'a>,
+}
+
+impl<'a> Checkpoint<'a> {
+    pub fn new(input: BitInput<'a>) -> Self {
+        Checkpoint { input }
+    }
+
+    // The input as it was when the checkpoint was made
+    pub fn restore(&self) -> BitInput<'a> {
+        self.input
+    }
+}
+
+// Runs a byte-level parser and returns its output along with the exact bytes it
+// consumed, which is useful for diagnostics (e.g. hexdumping the bad part of a packet).
+pub fn with_span<'a, P, O>(parser: P) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], (O, &'a [u8])>
+where
+    P: FnMut(&'a [u8]) -> IResult<&'a [u8], O>,
+{
+    map(consumed(parser), |(span, output)| (output, span))
+}
+
+// Try `primary`, and if it fails try `fallback` on the same input. Like `alt`,
+// only a recoverable `Err::Error` moves on to the fallback: an `Err::Failure`
+// (e.g. from inside a `cut`) means "this was the right branch, but it's broken",
+// so it's returned as is, and `Err::Incomplete` is passed through too.
+pub fn or_else<I, O, P1, P2>(mut primary: P1, mut fallback: P2) -> impl FnMut(I) -> IResult<I, O>
+where
+    I: Clone,
+    P1: FnMut(I) -> IResult<I, O>,
+    P2: FnMut(I) -> IResult<I, O>,
+{
+    move |i: I| match primary(i.clone()) {
+        Err(nom::Err::Error(_)) => fallback(i),
+        result => result,
+    }
+}
+
+// Names are sent on the wire as a sequence of labels, each prefixed by its length
+// and terminated by a zero length label (the root), e.g. "example.com" becomes
+// 7 e x a m p l e 3 c o m 0
+// See https://datatracker.ietf.org/doc/html/rfc1035#section-3.1
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+// Compression pointers have 14 bits for the offset they point to
+const MAX_POINTER_OFFSET: usize = 1 << 14;
+
+// Turn a dotted name like "example.com" (or "example.com.") into its wire format.
+// The root name is written as "." and encodes to a single zero byte, while an
+// empty string is an error rather than another way of writing the root.
+// Labels may contain escapes as in zone files (RFC 1035 section 5.1): `\.` is a
+// dot that doesn't end the label, `\DDD` is the byte with decimal value DDD, and a
+// backslash before any other character stands for that character.
+pub fn encode_name(name: &str) -> anyhow::Result<Vec<u8>> {
+    if name.is_empty() {
+        anyhow::bail!("Empty name, the root name is written as \".\"");
+    }
+    let labels = if name == "." {
+        Vec::new()
+    } else {
+        split_labels(name)?
+    };
+    encode_raw_labels(&labels).map_err(|e| anyhow::anyhow!("Invalid name {name:?}: {e}"))
+}
+
+// The same for a name that's already split into labels, e.g. a parsed
+// `Question::qname`. The labels are used as they are, without any escapes.
+pub fn encode_labels(labels: &[String]) -> anyhow::Result<Vec<u8>> {
+    encode_raw_labels(labels)
+}
+
+fn encode_raw_labels<L: AsRef<[u8]>>(labels: &[L]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    for label in labels {
+        let label = label.as_ref();
+        if label.is_empty() {
+            anyhow::bail!("Empty label");
+        }
+        if label.len() > MAX_LABEL_LEN {
+            anyhow::bail!(
+                "Label {:?} is longer than {MAX_LABEL_LEN} bytes",
+                String::from_utf8_lossy(label)
+            );
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+    if out.len() > MAX_NAME_LEN {
+        anyhow::bail!("Name is longer than {MAX_NAME_LEN} bytes");
+    }
+    Ok(out)
+}
+
+// Compression (RFC 1035 section 4.1.4): if the end of a name has already been
+// written somewhere in the message, write a 2 byte pointer to it instead of
+// repeating it. `written` maps the names (and name endings) written so far to
+// their offsets in the message, and `offset` is where this name is going to go.
+// The endings of this name that are written out in full are added to `written`,
+// so later names can point at them. Pointers only have room for 14 bit offsets,
+// so anything written past that can't be pointed at.
+pub fn encode_name_compressed(
+    labels: &[String],
+    offset: usize,
+    written: &mut HashMap<Vec<String>, usize>,
+) -> anyhow::Result<Vec<u8>> {
+    // Check the name as a whole, even if most of it ends up as a pointer
+    encode_labels(labels)?;
+    let mut out = Vec::new();
+    for (i, label) in labels.iter().enumerate() {
+        let suffix = &labels[i..];
+        // Only point at offsets that fit in a pointer's 14 bits, whatever `written` says
+        if let Some(&target) = written.get(suffix).filter(|&&t| t < MAX_POINTER_OFFSET) {
+            out.extend_from_slice(&(0xc000 | target as u16).to_be_bytes());
+            return Ok(out);
+        }
+        let here = offset + out.len();
+        if here < MAX_POINTER_OFFSET {
+            written.insert(suffix.to_vec(), here);
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    Ok(out)
+}
+
+// Split a presentation format name on its unescaped dots, decoding escapes along
+// the way. A single trailing dot is allowed and doesn't start another label.
+fn split_labels(name: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    let mut labels = Vec::new();
+    let mut label = Vec::new();
+    let mut ended_with_dot = false;
+    let mut bytes = name.bytes();
+    while let Some(byte) = bytes.next() {
+        ended_with_dot = byte == b'.';
+        match byte {
+            b'.' => labels.push(std::mem::take(&mut label)),
+            b'\\' => {
+                let escaped = match bytes.next() {
+                    Some(digit) if digit.is_ascii_digit() => {
+                        let digits: String = std::iter::once(digit)
+                            .chain(bytes.by_ref().take(2))
+                            .map(char::from)
+                            .collect();
+                        match digits.parse::<u8>() {
+                            Ok(value) if digits.len() == 3 => value,
+                            _ => anyhow::bail!("Invalid \\DDD escape in name {name:?}"),
+                        }
+                    }
+                    Some(other) => other,
+                    None => anyhow::bail!("Incomplete escape at the end of name {name:?}"),
+                };
+                label.push(escaped);
+            }
+            other => label.push(other),
+        }
+    }
+    if !name.is_empty() && !ended_with_dot {
+        labels.push(label);
+    }
+    Ok(labels)
+}
+
+// The inverse of `encode_name`: join parsed labels back into a dotted name.
+// The root name has no labels and is rendered as ".". Dots and backslashes inside
+// a label are escaped with a backslash, and any byte that isn't printable ASCII
+// (including spaces and non-ASCII text) is written as `\DDD`, so the result
+// encodes back to the same labels.
+pub fn decode_name(labels: &[String]) -> String {
+    if labels.is_empty() {
+        return ".".to_owned();
+    }
+    let escaped: Vec<String> = labels.iter().map(|label| escape_label(label)).collect();
+    escaped.join(".")
+}
+
+fn escape_label(label: &str) -> String {
+    let mut out = String::new();
+    for byte in label.bytes() {
+        match byte {
+            b'.' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            _ if byte.is_ascii_graphic() => out.push(byte as char),
+            _ => out.push_str(&format!("\\{byte:03}")),
+        }
+    }
+    out
+}
+
+// The name to look up in a PTR query to find the host name of an address.
+// IPv4 addresses go under in-addr.arpa with their bytes reversed, e.g. 192.0.2.1
+// becomes 1.2.0.192.in-addr.arpa (RFC 1035 section 3.5).
+pub fn ipv4_to_arpa(addr: Ipv4Addr) -> String {
+    let mut labels: Vec<String> = addr.octets().iter().rev().map(u8::to_string).collect();
+    labels.extend(["in-addr".to_owned(), "arpa".to_owned()]);
+    decode_name(&labels)
+}
+
+// IPv6 addresses go under ip6.arpa, one label per nibble, least significant
+// nibble first (RFC 3596 section 2.5).
+pub fn ipv6_to_arpa(addr: Ipv6Addr) -> String {
+    let mut labels: Vec<String> = addr
+        .octets()
+        .iter()
+        .rev()
+        .flat_map(|byte| [byte & 0xf, byte >> 4])
+        .map(|nibble| format!("{nibble:x}"))
+        .collect();
+    labels.extend(["ip6".to_owned(), "arpa".to_owned()]);
+    decode_name(&labels)
+}
+
+// An entry in the question section: the name being asked about, followed by
+// the type and class of records wanted.
+// See https://datatracker.ietf.org/doc/html/rfc1035#section-4.1.2
+// The labels are kept as `String`s, so a name with a label that isn't valid
+// UTF-8 is rejected with `MapRes`, even though any bytes are legal on the wire
+// (RFC 2181 section 11), e.g. what `encode_name("\\200.com")` produces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub qname: Vec<String>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+// The question section isn't bit-packed like the header, but nothing stops us
+// from reading whole bytes with the bit parsers: a byte is just 8 bits.
+fn take_byte(i: BitInput) -> IResult<BitInput, u8> {
+    take(8u8)(i)
+}
+
+// A single label: a length byte followed by that many bytes of text. Lengths
+// above 63 have their top bits set, which marks a compression pointer. Those point
+// elsewhere in the message, so we can't follow them here and reject them instead.
+// Labels that aren't UTF-8 are rejected too, see `Question`.
+fn take_label(i: BitInput) -> IResult<BitInput, String> {
+    let label_len = verify(take_byte, |&len| len > 0 && len as usize <= MAX_LABEL_LEN);
+    map_res(length_count(label_len, take_byte), String::from_utf8)(i)
+}
+
+impl Question {
+    // Every piece of a question is built from the sub-parsers above, so it can be
+    // parsed without ever leaving bit space.
+    pub fn deserialize(i: BitInput) -> IResult<BitInput, Self> {
+        // Labels until the zero length root label
+        let (i, (qname, _)) = many_till(take_label, tag(0u8, 8u8))(i)?;
+        let (i, qtype) = take_u16(i)?;
+        let (i, qclass) = take_u16(i)?;
+        let question = Question {
+            qname,
+            qtype,
+            qclass,
+        };
+        Ok((i, question))
+    }
+
+    // The inverse of `deserialize`: append the question in wire format to `out`.
+    // Fails, leaving `out` untouched, if the name can't be encoded (e.g. a label
+    // is too long). The root name has no labels and is written as a single 0.
+    pub fn serialize(&self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        out.extend(encode_labels(&self.qname)?);
+        out.extend(self.qtype.to_be_bytes());
+        out.extend(self.qclass.to_be_bytes());
+        Ok(())
+    }
+}
+
+// Parse the header and then as many questions as it says there are, all inside a
+// single `bits()` call. Bit-level parsers compose like any other nom parsers, so
+// there's no need to drop back to bytes between the two: the header's question
+// count is just a value we feed into `count`.
+pub fn parse_header_and_questions(i: &[u8]) -> IResult<&[u8], (Header, Vec<Question>)> {
+    nom::bits::bits(|i| {
+        let (i, header) = Header::deserialize(i)?;
+        let (i, questions) = count(Question::deserialize, header.question_count as usize)(i)?;
+        Ok((i, (header, questions)))
+    })(i)
+}
+
+// Packet captures and tools like `dig +qr` often show packets as hex, e.g.
+// "abcd 0100 0001 ...". Decode such a string (whitespace anywhere is ignored) and
+// parse the header and questions out of it. There's no full message parser yet,
+// so any answer records after the questions are left alone.
+pub fn parse_hex_packet(hex: &str) -> anyhow::Result<(Header, Vec<Question>)> {
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if let Some(&bad) = digits.iter().find(|b| !b.is_ascii_hexdigit()) {
+        anyhow::bail!("Invalid hex digit {:?} in {hex:?}", bad as char);
+    }
+    if !digits.len().is_multiple_of(2) {
+        anyhow::bail!("Odd number of hex digits in {hex:?}");
+    }
+    let bytes: Vec<u8> = digits
+        .chunks(2)
+        .map(|pair| {
+            // Both digits were checked above, so this can't fail
+            let pair = std::str::from_utf8(pair).unwrap();
+            u8::from_str_radix(pair, 16).unwrap()
+        })
+        .collect();
+    let (_, parsed) = parse_header_and_questions(&bytes)
+        .map_err(|e| anyhow::anyhow!("Invalid packet: {:?}", e.map(|e| e.code)))?;
+    Ok(parsed)
+}
+
+// Formats bytes in the classic `offset | hex bytes | ascii` layout, 16 bytes per line.
+// Non-printable bytes are shown as '.' in the ascii column. Handy for eyeballing a
+// packet that failed to parse.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{b:02x}")).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x} | {:<47} | {}\n",
+            line * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(bytes: &[u8]) -> Header {
+        let (_, header) = Header::deserialize((bytes, 0)).unwrap();
+        header
+    }
+
+    #[test]
+    fn test_parse_header_and_questions() {
+        // A query with ID 0xabcd, RD set and one question: example.com, type A, class IN
+        let mut bytes = vec![0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        bytes.extend(encode_name("example.com").unwrap());
+        bytes.extend([0, 1, 0, 1]);
+        bytes.push(0xff);
+
+        let (rest, (header, questions)) = parse_header_and_questions(&bytes).unwrap();
+        assert_eq!(rest, &[0xff]);
+        assert_eq!(header.id, QueryId(0xabcd));
+        assert!(header.wants_recursion());
+        assert_eq!(questions.len(), 1);
+        assert_eq!(decode_name(&questions[0].qname), "example.com");
+        assert_eq!(questions[0].qtype, 1);
+        assert_eq!(questions[0].qclass, 1);
+
+        // A compression pointer (0xc0 0x0c) in place of the name is rejected
+        let mut compressed = bytes[..12].to_vec();
+        compressed.extend([0xc0, 0x0c, 0, 1, 0, 1]);
+        assert!(parse_header_and_questions(&compressed).is_err());
+    }
+
+    #[test]
+    fn test_question_serialize() {
+        let question = Question {
+            qname: vec!["example".to_owned(), "com".to_owned()],
+            qtype: 28,
+            qclass: 1,
+        };
+        let mut bytes = Vec::new();
+        question.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, b"\x07example\x03com\x00\x00\x1c\x00\x01".to_vec());
+        let (_, parsed) = Question::deserialize((&bytes, 0)).unwrap();
+        assert_eq!(parsed, question);
+
+        // The root name is just the zero length label
+        let root = Question {
+            qname: Vec::new(),
+            qtype: 2,
+            qclass: 1,
+        };
+        let mut bytes = Vec::new();
+        root.serialize(&mut bytes).unwrap();
+        assert_eq!(bytes, vec![0, 0, 2, 0, 1]);
+        let (_, parsed) = Question::deserialize((&bytes, 0)).unwrap();
+        assert_eq!(parsed, root);
+
+        // A legal label, but not UTF-8, so it can't be a `String`
+        let mut not_utf8 = encode_name("\\200.com").unwrap();
+        not_utf8.extend([0, 1, 0, 1]);
+        assert_eq!(
+            Question::deserialize((&not_utf8, 0)).unwrap_err(),
+            nom::Err::Error(nom::error::Error::new(
+                (not_utf8.as_ref(), 0),
+                nom::error::ErrorKind::MapRes
+            ))
+        );
+
+        let too_long = Question {
+            qname: vec!["a".repeat(64)],
+            ..root
+        };
+        assert!(too_long.serialize(&mut bytes).is_err());
+        assert_eq!(bytes.len(), 5);
+    }
+
+    #[test]
+    fn test_or_else() {
+        let input: BitInput = (&[0b0010_0000], 0);
+        let one = |i| verify(take_nibble, |&n| n == 1)(i);
+
+        // An Error in the primary falls through to the fallback
+        let (_, nibble) = or_else(one, take_nibble)(input).unwrap();
+        assert_eq!(nibble, 2);
+
+        // A Failure isn't caught
+        let result = or_else(cut(one), take_nibble)(input);
+        assert!(matches!(result, Err(nom::Err::Failure(_))));
+
+        // And the fallback isn't needed when the primary succeeds
+        let (_, nibble) = or_else(take_nibble, one)(input).unwrap();
+        assert_eq!(nibble, 2);
+    }
+
+    #[test]
+    fn test_header_flags() {
+        // QR, RD and RA set
+        let response = header(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        let flags = response.flags();
+        assert!(flags.is_response);
+        assert!(flags.recursion_desired);
+        assert!(flags.recursion_available);
+        assert!(!flags.authoritative_answer);
+        assert_eq!(flags, Header::decode_flags(0x8180).unwrap());
+
+        // A query with only RD set has different flags
+        let query = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_ne!(query.flags(), flags);
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        // Opcode 5 (DSO, not one we know) and rcode 9 (NOTAUTH, ditto)
+        let bytes = [0x12, 0x34, 0x28, 0x09, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert!(Header::deserialize((&bytes, 0)).is_err());
+
+        let (_, (header, warnings)) = Header::parse_lenient((&bytes, 0)).unwrap();
+        assert_eq!(header.flags().opcode, Opcode::Unknown(5));
+        assert_eq!(header.flags().resp_code, ResponseCode::Unknown(9));
+        assert_eq!(
+            warnings,
+            vec![Warning::UnknownOpcode(5), Warning::UnknownResponseCode(9)]
+        );
+
+        // Z = 0b001 is let through too
+        let bytes = [0x12, 0x34, 0x01, 0x10, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (_, (_, warnings)) = Header::parse_lenient((&bytes, 0)).unwrap();
+        assert_eq!(warnings, vec![Warning::NonzeroZ(1)]);
+
+        // Nothing to warn about in a normal header
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (_, (_, warnings)) = Header::parse_lenient((&bytes, 0)).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn test_deserialize_capture_z() {
+        // RD set and Z = 0b010
+        let bytes = [0x12, 0x34, 0x01, 0x20, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (_, (header, z)) = Header::deserialize_capture_z((&bytes, 0)).unwrap();
+        assert_eq!(z, 0b010);
+        assert!(header.wants_recursion());
+        assert_eq!(header.flags().resp_code, ResponseCode::NoError);
+
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        let (_, (_, z)) = Header::deserialize_capture_z((&bytes, 0)).unwrap();
+        assert_eq!(z, 0);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        // A query claiming an answer, with opcode 5 and Z = 0b001
+        let bytes = [0x12, 0x34, 0x28, 0x10, 0, 1, 0, 1, 0, 0, 0, 0];
+
+        let strict = Header::deserialize_with(ParseOptions::strict(), (&bytes, 0));
+        assert!(strict.is_err());
+
+        let (_, header) = Header::deserialize_with(ParseOptions::lenient(), (&bytes, 0)).unwrap();
+        assert_eq!(header.flags().opcode, Opcode::Unknown(5));
+        assert_eq!(header.answer_count, 1);
+
+        // With only the opcode fixed, strict parsing still fails on the Z bits...
+        let bytes = [0x12, 0x34, 0x00, 0x10, 0, 1, 0, 1, 0, 0, 0, 0];
+        let z_allowed = ParseOptions {
+            allow_nonzero_z: true,
+            ..ParseOptions::strict()
+        };
+        let result = Header::deserialize_with(ParseOptions::strict(), (&bytes, 0));
+        assert!(matches!(result, Err(nom::Err::Error(_))));
+        // ...and once those are allowed, on the counts, which can't be backtracked
+        let result = Header::deserialize_with(z_allowed, (&bytes, 0));
+        assert!(matches!(result, Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_deserialize_into() {
+        let mut buffer = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let second = [0xab, 0xcd, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        Header::deserialize_into((&second, 0), &mut buffer).unwrap();
+        assert_eq!(buffer.id, QueryId(0xabcd));
+        assert_eq!(buffer.answer_count, 2);
+        assert!(buffer.offers_recursion());
+
+        // A failed parse leaves the buffer alone
+        assert!(Header::deserialize_into((&second[..4], 0), &mut buffer).is_err());
+        assert_eq!(buffer.id, QueryId(0xabcd));
+    }
+
+    #[test]
+    fn test_parse_hex_packet() {
+        // A query for example.com, type A, class IN, as dig would print it
+        let hex = "abcd 0100 0001 0000 0000 0000
+                   0765 7861 6d70 6c65 0363 6f6d 00
+                   0001 0001";
+        let (header, questions) = parse_hex_packet(hex).unwrap();
+        assert_eq!(header.id, QueryId(0xabcd));
+        assert_eq!(decode_name(&questions[0].qname), "example.com");
+
+        // Odd length
+        let err = parse_hex_packet("abc").unwrap_err();
+        assert!(err.to_string().contains("Odd number"));
+        // Not hex. A `+` sign would be accepted by from_str_radix, but isn't a digit.
+        let err = parse_hex_packet("abzz").unwrap_err();
+        assert!(err.to_string().contains("Invalid hex digit"));
+        let err = parse_hex_packet("+a").unwrap_err();
+        assert!(err.to_string().contains("Invalid hex digit"));
+    }
+
+    #[test]
+    fn test_eq_ignoring_id() {
+        let query = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let same_but_id = header(&[0xab, 0xcd, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(query.eq_ignoring_id(&same_but_id));
+        assert_ne!(query, same_but_id);
+        assert_eq!(query.diff(&same_but_id), vec!["id: 0x1234 -> 0xabcd"]);
+
+        let response = header(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert!(!query.eq_ignoring_id(&response));
+    }
+
+    #[test]
+    fn test_header_diff() {
+        // A query with RD set, and a recursive response with two answers
+        let query = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let response = header(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]);
+        assert_eq!(
+            query.diff(&response),
+            vec!["qr: false -> true", "ra: false -> true", "ancount: 0 -> 2"]
+        );
+        assert!(query.diff(&query).is_empty());
+
+        let nxdomain = header(&[0xab, 0xcd, 0x81, 0x83, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            response.diff(&nxdomain),
+            vec![
+                "id: 0x1234 -> 0xabcd",
+                "rcode: NoError -> NameError",
+                "ancount: 2 -> 0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reverse_names() {
+        assert_eq!(
+            ipv4_to_arpa(Ipv4Addr::new(192, 0, 2, 1)),
+            "1.2.0.192.in-addr.arpa"
+        );
+        assert_eq!(
+            ipv6_to_arpa(Ipv6Addr::LOCALHOST),
+            format!("1{}.ip6.arpa", ".0".repeat(31))
+        );
+        assert_eq!(
+            ipv6_to_arpa("2001:db8::567:89ab".parse().unwrap()),
+            "b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa"
+        );
+    }
+
+    #[test]
+    fn test_parse_exact() {
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, 0xff];
+        let header = Header::parse_exact(&bytes[..12]).unwrap();
+        assert_eq!(header.id, QueryId(0x1234));
+        // One byte too many
+        let err = Header::parse_exact(&bytes).unwrap_err();
+        assert!(err.to_string().contains("Eof"));
+        // and one too few
+        assert!(Header::parse_exact(&bytes[..11]).is_err());
+    }
+
+    #[test]
+    fn test_recursion_flags() {
+        // ID 0x1234, RD set, one question
+        let query = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(query.wants_recursion());
+        assert!(!query.offers_recursion());
+
+        // QR, RD and RA set
+        let recursive_response = header(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert!(recursive_response.offers_recursion());
+        assert!(!recursion_mismatch(&query, &recursive_response));
+
+        // QR and RD set, but RA cleared
+        let iterative_response = header(&[0x12, 0x34, 0x81, 0x00, 0, 1, 0, 0, 0, 1, 0, 0]);
+        assert!(!iterative_response.offers_recursion());
+        assert!(recursion_mismatch(&query, &iterative_response));
+
+        // A query that didn't ask for recursion can't be mismatched
+        let plain_query = header(&[0x12, 0x34, 0x00, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert!(!recursion_mismatch(&plain_query, &iterative_response));
+    }
+
+    #[test]
+    fn test_query_id() {
+        let query = header(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let response = header(&[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        let stray = header(&[0x0a, 0xbc, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert!(response.id.matches(&query.id));
+        assert!(!stray.id.matches(&query.id));
+        assert_eq!(query.id.to_string(), "0x1234");
+        assert_eq!(stray.id.to_string(), "0x0abc");
+    }
+
+    #[test]
+    fn test_deserialize_strict() {
+        // A query (QR=0) with an answer
+        let bytes = [0x12, 0x34, 0x01, 0x00, 0, 1, 0, 1, 0, 0, 0, 0];
+        assert!(Header::deserialize((&bytes, 0)).is_ok());
+        // The Failure points at the start of the bad header, not the end
+        assert_eq!(
+            Header::deserialize_strict((&bytes, 0)),
+            Err(nom::Err::Failure(nom::error::Error::new(
+                (bytes.as_ref(), 0),
+                nom::error::ErrorKind::Verify
+            )))
+        );
+
+        // The same header as a response is fine
+        let bytes = [0x12, 0x34, 0x81, 0x00, 0, 1, 0, 1, 0, 0, 0, 0];
+        assert!(Header::deserialize_strict((&bytes, 0)).is_ok());
+    }
+
+    #[test]
+    fn test_new_response() {
+        // Status request with RD set and one question
+        let query = header(&[0xbe, 0xef, 0x11, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let response = Header::new_response(&query);
+        assert_eq!(response.id, QueryId(0xbeef));
+        assert!(response.is_response);
+        assert!(matches!(response.opcode, Opcode::Status));
+        assert!(response.wants_recursion());
+        assert!(matches!(response.resp_code, ResponseCode::NoError));
+        assert_eq!(response.question_count, 1);
+        assert_eq!(response.answer_count, 0);
+    }
+
+    #[test]
+    fn test_header_eq() {
+        let bytes = [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        let response = header(&bytes);
+        assert_eq!(response, header(&bytes));
+        assert_eq!(response.clone(), response);
+        assert_eq!(Header::try_from(&bytes[..]).unwrap(), response);
+
+        // Roundtrip a response built from a query through its wire format
+        let query = header(&[0xbe, 0xef, 0x11, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let built = Header::new_response(&query);
+        assert_eq!(
+            header(&[0xbe, 0xef, 0x91, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]),
+            built
+        );
+    }
+
+    #[test]
+    fn test_header_flag_bits() {
+        let flags = HeaderFlagBits::QR | HeaderFlagBits::RD | HeaderFlagBits::RA;
+        assert_eq!(flags.to_word(), 0x8180);
+        assert!(flags.contains(HeaderFlagBits::RD));
+        assert!(flags.contains(HeaderFlagBits::QR | HeaderFlagBits::RA));
+        assert!(!flags.contains(HeaderFlagBits::AA));
+
+        // The opcode (status) and rcode (NXDOMAIN) bits are dropped
+        let flags = HeaderFlagBits::from_word(0x9583);
+        assert_eq!(
+            flags,
+            HeaderFlagBits::QR | HeaderFlagBits::AA | HeaderFlagBits::RD | HeaderFlagBits::RA
+        );
+        assert_eq!(HeaderFlagBits::TC.to_word(), 0x0200);
+        assert_eq!(HeaderFlagBits::default().to_word(), 0);
+    }
+
+    #[test]
+    fn test_decode_flags() {
+        // QR, AA, RD, RA set, NXDOMAIN
+        let flags = Header::decode_flags(0x8583).unwrap();
+        assert!(flags.is_response);
+        assert!(matches!(flags.opcode, Opcode::Query));
+        assert!(flags.authoritative_answer);
+        assert!(!flags.truncation);
+        assert!(flags.recursion_desired);
+        assert!(flags.recursion_available);
+        assert!(matches!(flags.resp_code, ResponseCode::NameError));
+
+        // Opcode 3 is unassigned
+        assert!(Header::decode_flags(0x1800).is_err());
+    }
+
+    #[test]
+    fn test_header_try_from() {
+        let bytes = [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        let header = Header::try_from(&bytes[..]).unwrap();
+        assert_eq!(header.id, QueryId(0x1234));
+        assert_eq!(header.question_count, 1);
+        assert_eq!(header.answer_count, 2);
+
+        // Too short
+        assert!(Header::try_from(&bytes[..5]).is_err());
+        // Opcode 15 isn't a known opcode
+        let bytes = [0x12, 0x34, 0x78, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        assert!(Header::try_from(&bytes[..]).is_err());
+    }
+
+    #[test]
+    fn test_parse_with_raw() {
+        let bytes = [0x12, 0x34, 0x85, 0x83, 0, 1, 0, 0, 0, 1, 0, 0, 0xff];
+        let (header, raw_flags) = Header::parse_with_raw(&bytes).unwrap();
+        assert_eq!(raw_flags, bytes[2..4]);
+        assert!(header.authoritative_answer);
+        assert!(matches!(header.resp_code, ResponseCode::NameError));
+        assert!(Header::parse_with_raw(&bytes[..3]).is_err());
+    }
+
+    #[test]
+    fn test_take_enum() {
+        let input = ([0b0010_0001, 0b1100_0000].as_ref(), 0);
+        let (input, opcode) = take_enum::<Opcode>(4)(input).unwrap();
+        assert!(matches!(opcode, Opcode::Status));
+        let (input, opcode) = take_enum::<Opcode>(4)(input).unwrap();
+        assert!(matches!(opcode, Opcode::InverseQuery));
+        // 0b11 isn't a known opcode
+        assert!(take_enum::<Opcode>(2)(input).is_err());
+    }
+
+    #[test]
+    fn test_rcode_user_message() {
+        let messages = [
+            (ResponseCode::NoError, "The query completed successfully"),
+            (
+                ResponseCode::FormatError,
+                "The server could not understand the query",
+            ),
+            (
+                ResponseCode::ServerFailure,
+                "The server failed to process the query",
+            ),
+            (ResponseCode::NameError, "The domain name does not exist"),
+            (
+                ResponseCode::NotImplemented,
+                "The server does not support this kind of query",
+            ),
+            (
+                ResponseCode::Refused,
+                "The server refused to answer the query",
+            ),
+        ];
+        for (rcode, message) in messages {
+            assert_eq!(rcode.user_message(), message);
+        }
+    }
+
+    #[test]
+    fn test_classify_rcode() {
+        assert_eq!(ResponseCode::classify(3), RcodeClass::Assigned);
+        assert_eq!(ResponseCode::classify(23), RcodeClass::Assigned);
+        assert_eq!(ResponseCode::classify(3841), RcodeClass::Reserved);
+        assert_eq!(ResponseCode::classify(65535), RcodeClass::Reserved);
+        assert_eq!(ResponseCode::classify(12), RcodeClass::Unassigned);
+        assert_eq!(ResponseCode::classify(24), RcodeClass::Unassigned);
+    }
+
+    #[test]
+    fn test_take_endianness() {
+        let bytes = [0x12, 0x34, 0x56, 0x78];
+        assert_eq!(take_u16((&bytes, 0)).unwrap().1, 0x1234);
+        assert_eq!(take_u16_le((&bytes, 0)).unwrap().1, 0x3412);
+        assert_eq!(take_u32((&bytes, 0)).unwrap().1, 0x1234_5678);
+        assert_eq!(take_u32_le((&bytes, 0)).unwrap().1, 0x7856_3412);
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        // An opcode of 15, which isn't valid, followed by one more nibble
+        let input = ([0b1111_0001].as_ref(), 0);
+        let checkpoint = Checkpoint::new(input);
+        assert!(take_enum::<Opcode>(4)(input).is_err());
+
+        // Go back and read it as a plain number instead
+        let (rest, nibble) = take_nibble(checkpoint.restore()).unwrap();
+        assert_eq!(nibble, 15);
+        assert_eq!(rest, ([0b1111_0001].as_ref(), 4));
+    }
+
+    #[test]
+    fn test_with_span() {
+        let bytes = [
+            0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0, // header
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', // rest of the packet
+        ];
+        let parse_header = nom::bits::bits(Header::deserialize);
+        let (rest, (header, span)) = with_span(parse_header)(&bytes).unwrap();
+        assert_eq!(header.id, QueryId(0x1234));
+        assert_eq!(span, &bytes[..12]);
+        assert_eq!(rest, &bytes[12..]);
+    }
+
+    #[test]
+    fn test_encode_name() {
+        let expected = b"\x07example\x03com\x00".to_vec();
+        assert_eq!(encode_name("example.com").unwrap(), expected);
+        assert_eq!(encode_name("example.com.").unwrap(), expected);
+        assert_eq!(encode_name(".").unwrap(), vec![0]);
+        assert!(encode_name("").is_err());
+
+        let long_label = "a".repeat(64);
+        assert!(encode_name(&format!("{long_label}.com")).is_err());
+        assert!(encode_name(&"a".repeat(63)).is_ok());
+        // Four 63 byte labels add up to 257 bytes on the wire
+        let long_name = vec!["a".repeat(63); 4].join(".");
+        assert!(encode_name(&long_name).is_err());
+        assert!(encode_name("example..com").is_err());
+    }
+
+    #[test]
+    fn test_encode_labels() {
+        let labels = |name: &str| -> Vec<String> { name.split('.').map(str::to_owned).collect() };
+        assert_eq!(
+            encode_labels(&labels("www.example.com")).unwrap(),
+            b"\x03www\x07example\x03com\x00".to_vec()
+        );
+        assert_eq!(encode_labels(&[]).unwrap(), vec![0]);
+        assert!(encode_labels(&["a".repeat(64)]).is_err());
+        assert!(encode_labels(&labels("example..com")).is_err());
+    }
+
+    #[test]
+    fn test_encode_name_compressed() {
+        let labels = |name: &str| -> Vec<String> { name.split('.').map(str::to_owned).collect() };
+        let mut written = HashMap::new();
+
+        // The first name goes out in full, right after the 12 byte header
+        let first = encode_name_compressed(&labels("www.example.com"), 12, &mut written).unwrap();
+        assert_eq!(first, b"\x03www\x07example\x03com\x00".to_vec());
+
+        // The second shares example.com, which started 4 bytes into the first name
+        let offset = 12 + first.len();
+        let second =
+            encode_name_compressed(&labels("mail.example.com"), offset, &mut written).unwrap();
+        assert_eq!(second, b"\x04mail\xc0\x10".to_vec());
+
+        // A name that's been written whole is just a pointer
+        let offset = offset + second.len();
+        let third =
+            encode_name_compressed(&labels("mail.example.com"), offset, &mut written).unwrap();
+        assert_eq!(third, vec![0xc0, 0x1d]);
+
+        // Nothing in common, so nothing to point at
+        let other = encode_name_compressed(&labels("example.org"), 100, &mut written).unwrap();
+        assert_eq!(other, encode_labels(&labels("example.org")).unwrap());
+
+        // An offset too big for a pointer is written out in full instead
+        let mut written = HashMap::new();
+        written.insert(labels("example.com"), MAX_POINTER_OFFSET);
+        let name = encode_name_compressed(&labels("example.com"), 12, &mut written).unwrap();
+        assert_eq!(name, encode_labels(&labels("example.com")).unwrap());
+
+        let long_label = vec!["a".repeat(64), "com".to_owned()];
+        assert!(encode_name_compressed(&long_label, 0, &mut written).is_err());
+    }
+
+    #[test]
+    fn test_encode_name_escapes() {
+        assert_eq!(
+            encode_name("a\\.b.com").unwrap(),
+            b"\x03a.b\x03com\x00".to_vec()
+        );
+        assert_eq!(
+            encode_name("\\065\\\\b.").unwrap(),
+            b"\x03A\\b\x00".to_vec()
+        );
+        // An escaped trailing dot is part of the last label
+        assert_eq!(encode_name("com\\.").unwrap(), b"\x04com.\x00".to_vec());
+
+        assert!(encode_name("example.com\\").is_err());
+        assert!(encode_name("a\\25").is_err());
+        assert!(encode_name("a\\256").is_err());
+    }
+
+    #[test]
+    fn test_decode_name() {
+        let labels = vec!["example".to_owned(), "com".to_owned()];
+        assert_eq!(decode_name(&labels), "example.com");
+        assert_eq!(decode_name(&[]), ".");
+
+        let labels = vec!["a.b".to_owned(), "c\\d e".to_owned(), "café".to_owned()];
+        assert_eq!(decode_name(&labels), "a\\.b.c\\\\d\\032e.caf\\195\\169");
+        // Back through encode_name gives the same three labels
+        assert_eq!(
+            encode_name(&decode_name(&labels)).unwrap(),
+            encode_labels(&labels).unwrap()
+        );
+    }
+
+    #[cfg(feature = "logging")]
+    #[test]
+    fn test_trace_logging() {
+        use std::sync::Mutex;
+
+        // Collects every message logged, from any test
+        struct CapturingLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let message = format!("{} {}", record.level(), record.args());
+                self.0.lock().unwrap().push(message);
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        header(&[0x7e, 0x57, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let messages = LOGGER.0.lock().unwrap();
+        let ours: Vec<&String> = messages.iter().filter(|m| m.contains("0x7e57")).collect();
+        assert_eq!(ours.len(), 2);
+        assert!(ours[0].starts_with("TRACE Parsed flags of message 0x7e57"));
+        assert_eq!(
+            ours[1],
+            "TRACE Parsed header of message 0x7e57: 1 questions, 0 answers, \
+             0 authority records, 0 additional records"
+        );
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let bytes = b"Hello, DNS!\x00\x01\x02\xff\x7fparser";
+        let expected = concat!(
+            "00000000 | 48 65 6c 6c 6f 2c 20 44 4e 53 21 00 01 02 ff 7f | Hello, DNS!.....\n",
+            "00000010 | 70 61 72 73 65 72                               | parser\n",
+        );
+        assert_eq!(hexdump(bytes), expected);
+        assert_eq!(hexdump(&[]), "");
     }
 }
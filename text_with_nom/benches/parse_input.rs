@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use text_with_nom::{parse_input_unwrap, parse_numbers, parse_numbers_fast};
+
+// A day 5 style input, much bigger than the real puzzle input
+fn generate_input(lines: u32) -> String {
+    (0..lines)
+        .map(|i| {
+            let (a, b) = (i * 7 % 1000, i * 13 % 1000);
+            format!("{a},{b} -> {b},{a}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn bench_parse_input(c: &mut Criterion) {
+    let input = generate_input(100_000);
+    c.bench_function("parse_input 100k lines", |b| {
+        b.iter(|| parse_input_unwrap(black_box(&input)))
+    });
+}
+
+fn bench_parse_numbers(c: &mut Criterion) {
+    let numbers: Vec<String> = (0..10_000u32).map(|i| (i * 104_729).to_string()).collect();
+    let mut group = c.benchmark_group("parse_numbers");
+    group.bench_function("from_str", |b| {
+        b.iter(|| {
+            for n in &numbers {
+                black_box(parse_numbers(black_box(n)).unwrap());
+            }
+        })
+    });
+    group.bench_function("fast", |b| {
+        b.iter(|| {
+            for n in &numbers {
+                black_box(parse_numbers_fast(black_box(n)).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_input, bench_parse_numbers);
+criterion_main!(benches);
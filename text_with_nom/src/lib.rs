@@ -1,12 +1,26 @@
 // AOC 2021 day 5 example
+//
+// Convention: every public parser returns an `IResult`, so it can be composed with
+// other nom parsers. Where it's handy to just get the value, there's a separate
+// `*_unwrap` version (e.g. `parse_input_unwrap`) that panics on a parse error.
+// The exceptions are `parse_input_lenient` and `lines_iter`, which deal with bad
+// lines themselves instead of failing the whole parse, and `parse_input_gz`, which
+// reads a file and so returns an `io::Result`.
 use nom::{
-    bytes::complete::tag,
-    character::complete::{char, digit1, line_ending},
-    combinator::{map, map_res},
-    multi::separated_list1,
-    sequence::separated_pair,
+    branch::alt,
+    bytes::complete::{tag, tag_no_case, take_until, take_while1},
+    character::complete::{anychar, char, digit1, line_ending, not_line_ending, space0, space1},
+    combinator::{all_consuming, cut, eof, map, map_res, opt, peek, recognize, verify},
+    error::{Error, ErrorKind},
+    multi::{many_m_n, many_till, separated_list1},
+    number::complete::double,
+    sequence::{pair, preceded, separated_pair, terminated},
     IResult,
 };
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::ops::Add;
 use std::str::FromStr;
 
 // Parse a `u32` from the start of the input string
@@ -14,8 +28,92 @@ pub fn parse_numbers(input: &str) -> IResult<&str, u32> {
     map_res(digit1, u32::from_str)(input)
 }
 
+// Like `parse_numbers`, but allows single underscores between the digits as
+// thousands separators, e.g. `1_000`. `parse_numbers` itself stays strict and
+// stops at the first underscore.
+pub fn parse_numbers_lenient(input: &str) -> IResult<&str, u32> {
+    let digits_and_underscores = recognize(separated_list1(char('_'), digit1));
+    map_res(digits_and_underscores, |digits: &str| {
+        u32::from_str(&digits.replace('_', ""))
+    })(input)
+}
+
+// Same as `parse_numbers`, but accumulates the digits by hand instead of slicing
+// out a `&str` and going through `u32::from_str`. See benches/parse_input.rs.
+pub fn parse_numbers_fast(input: &str) -> IResult<&str, u32> {
+    let (len, n) = take_u32_digits(input.as_bytes())
+        .map_err(|kind| nom::Err::Error(Error::new(input, kind)))?;
+    Ok((&input[len..], n))
+}
+
+// Add up the ASCII digits at the start of `input` into a `u32`, returning how many
+// bytes they took up. Fails like `map_res(digit1, ..)` would: `Digit` if there are
+// no digits, `MapRes` if the number doesn't fit. Shared by the parsers that skip
+// `u32::from_str`.
+fn take_u32_digits(input: &[u8]) -> Result<(usize, u32), ErrorKind> {
+    let len = input.iter().take_while(|b| b.is_ascii_digit()).count();
+    if len == 0 {
+        return Err(ErrorKind::Digit);
+    }
+    let n = input[..len]
+        .iter()
+        .try_fold(0u32, |n, digit| {
+            n.checked_mul(10)?.checked_add(u32::from(digit - b'0'))
+        })
+        .ok_or(ErrorKind::MapRes)?;
+    Ok((len, n))
+}
+
+// Parse a `u64` written in hex (`0xFF`), binary (`0b1010`), octal (`0o17`) or
+// plain decimal (`42`), going by its prefix. A lone `0` is decimal zero. Once a
+// prefix has been seen the number has to follow it, so `0xZZ` is an error rather
+// than a 0 followed by `xZZ`.
+pub fn parse_number_auto(input: &str) -> IResult<&str, u64> {
+    let with_radix = |prefix: &'static str, radix: u32| {
+        preceded(
+            tag_no_case(prefix),
+            cut(map_res(
+                take_while1(move |c: char| c.is_digit(radix)),
+                move |digits| u64::from_str_radix(digits, radix),
+            )),
+        )
+    };
+    alt((
+        with_radix("0x", 16),
+        with_radix("0b", 2),
+        with_radix("0o", 8),
+        map_res(digit1, u64::from_str),
+    ))(input)
+}
+
+// Parse two things separated by `sep`, and combine them into one value, e.g. the
+// x and y of a Point. Saves writing out `map(separated_pair(..), ..)` for every
+// type that's made of a pair.
+pub fn parse_pair<'a, A, B, O, P1, P2, F>(
+    first: P1,
+    sep: char,
+    second: P2,
+    combine: F,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where
+    P1: FnMut(&'a str) -> IResult<&'a str, A>,
+    P2: FnMut(&'a str) -> IResult<&'a str, B>,
+    F: FnMut((A, B)) -> O,
+{
+    map(separated_pair(first, char(sep), second), combine)
+}
+
+// Parse rows of space separated numbers, one row per line, e.g. a heightmap.
+// Every row has to be the same length, otherwise it's not a grid and parsing fails.
+pub fn parse_int_grid(input: &str) -> IResult<&str, Vec<Vec<u32>>> {
+    let rows = separated_list1(line_ending, separated_list1(space1, parse_numbers));
+    verify(rows, |rows: &Vec<Vec<u32>>| {
+        rows.iter().all(|row| row.len() == rows[0].len())
+    })(input)
+}
+
 // a point in 2D space
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct Point {
     pub x: u32,
     pub y: u32,
@@ -23,20 +121,224 @@ pub struct Point {
 
 impl Point {
     fn parse(input: &str) -> IResult<&str, Self> {
-        // This parser outputs a (u32, u32).
-        // It uses the `parse_numbers` parser
-        // and the `separated_pair` combinator
-        let parse_two_numbers = separated_pair(parse_numbers, char(','), parse_numbers);
+        Self::parse_with_sep(',')(input)
+    }
+
+    // Parse a string that must be exactly one point, with nothing after it
+    pub fn parse_strict(input: &str) -> IResult<&str, Self> {
+        all_consuming(Self::parse)(input)
+    }
+
+    // Build a point parser for coordinates separated by `sep`, e.g. `3;4` or `3 4`
+    pub fn parse_with_sep<'a>(sep: char) -> impl FnMut(&'a str) -> IResult<&'a str, Self> {
+        // Parse two numbers with `parse_numbers`, separated by `sep`,
+        // and map the (u32, u32) into a Point.
+        parse_pair(parse_numbers, sep, parse_numbers, |(x, y)| Point { x, y })
+    }
+
+    // Parse a point from raw bytes, e.g. `b"12,34"`. The digits are known to be
+    // ASCII, so they're added up directly without going through a `&str` first.
+    pub fn parse_bytes(input: &[u8]) -> IResult<&[u8], Self> {
+        fn parse_number(i: &[u8]) -> IResult<&[u8], u32> {
+            let (len, n) =
+                take_u32_digits(i).map_err(|kind| nom::Err::Error(Error::new(i, kind)))?;
+            Ok((&i[len..], n))
+        }
+        map(
+            separated_pair(parse_number, char(','), parse_number),
+            |(x, y)| Point { x, y },
+        )(input)
+    }
+
+    // Cap the coordinates at the given maxima, so the point can safely index a grid
+    pub fn clamp(&self, max_x: u32, max_y: u32) -> Point {
+        Point {
+            x: self.x.min(max_x),
+            y: self.y.min(max_y),
+        }
+    }
+
+    // Whether the point lies in the rectangle between `min` and `max`, edges included
+    pub fn in_rect(&self, min: &Point, max: &Point) -> bool {
+        (min.x..=max.x).contains(&self.x) && (min.y..=max.y).contains(&self.y)
+    }
+
+    // Position of the point in a flat, row by row grid `width` points wide.
+    // Only round trips through `from_index` if x < width.
+    pub fn to_index(&self, width: u32) -> usize {
+        self.y as usize * width as usize + self.x as usize
+    }
+
+    // The inverse of `to_index`. None for a zero `width`, or if the row number
+    // doesn't fit in a `u32`.
+    pub fn from_index(idx: usize, width: u32) -> Option<Point> {
+        let width = width as usize;
+        if width == 0 {
+            return None;
+        }
+        Some(Point {
+            x: (idx % width) as u32,
+            y: u32::try_from(idx / width).ok()?,
+        })
+    }
+
+    // Move the point by `delta`. None if the move would leave the range of a `u32`
+    // in either direction, e.g. go below zero.
+    pub fn apply(&self, delta: &Delta) -> Option<Point> {
+        let x = i64::from(self.x) + i64::from(delta.dx);
+        let y = i64::from(self.y) + i64::from(delta.dy);
+        Some(Point {
+            x: u32::try_from(x).ok()?,
+            y: u32::try_from(y).ok()?,
+        })
+    }
+
+    // The points directly above, left, right and below this one (in that order),
+    // leaving out any that would be off the edge of the `u32` grid
+    pub fn neighbors4(&self) -> impl Iterator<Item = Point> {
+        self.neighbors(&NEIGHBORS4)
+    }
+
+    // Like `neighbors4`, plus the diagonals, in reading order
+    pub fn neighbors8(&self) -> impl Iterator<Item = Point> {
+        self.neighbors(&NEIGHBORS8)
+    }
+
+    fn neighbors(&self, deltas: &'static [(i32, i32)]) -> impl Iterator<Item = Point> {
+        let p = *self;
+        deltas
+            .iter()
+            .filter_map(move |&(dx, dy)| p.apply(&Delta { dx, dy }))
+    }
 
-        // Map the (u32, u32) into a Point.
-        map(parse_two_numbers, |(x, y)| Point { x, y })(input)
+    // Add two points coordinate by coordinate, or None if either sum overflows a `u32`
+    pub fn checked_add(&self, other: &Point) -> Option<Point> {
+        Some(Point {
+            x: self.x.checked_add(other.x)?,
+            y: self.y.checked_add(other.y)?,
+        })
+    }
+
+    // Add two points coordinate by coordinate, stopping at `u32::MAX` instead of overflowing
+    pub fn saturating_add(&self, other: &Point) -> Point {
+        Point {
+            x: self.x.saturating_add(other.x),
+            y: self.y.saturating_add(other.y),
+        }
+    }
+}
+
+// Plain `+` behaves like it does for the integers inside: it panics on overflow in
+// debug builds and wraps in release builds. Use `checked_add` or `saturating_add`
+// when walking a grid that might reach `u32::MAX`.
+impl Add for Point {
+    type Output = Point;
+
+    fn add(self, other: Point) -> Point {
+        Point {
+            x: self.x + other.x,
+            y: self.y + other.y,
+        }
     }
 }
 
-// A line spanning two points
+// The moves to each neighbour of a point, with y growing downwards like the puzzle grid
+static NEIGHBORS4: [(i32, i32); 4] = [(0, -1), (-1, 0), (1, 0), (0, 1)];
+static NEIGHBORS8: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+// A relative move like `+3,-2`
 #[derive(Debug, Eq, PartialEq)]
+pub struct Delta {
+    pub dx: i32,
+    pub dy: i32,
+}
+
+impl Delta {
+    // Parse two signed numbers separated by a comma. Signs are optional on both.
+    pub fn parse(input: &str) -> IResult<&str, Self> {
+        let parse_signed = nom::character::complete::i32;
+        let parse_two_numbers = separated_pair(parse_signed, char(','), parse_signed);
+        map(parse_two_numbers, |(dx, dy)| Delta { dx, dy })(input)
+    }
+}
+
+// Parse a point given in polar form, `r@degrees`, e.g. `5@90`. The angle is
+// measured from the x axis towards the y axis. Each coordinate is rounded to the
+// nearest whole number (halves away from zero), so `5@45` is 4,4. A negative
+// radius is an error, and so is an angle that lands outside the positive quadrant.
+pub fn parse_polar(input: &str) -> IResult<&str, Point> {
+    let radius = verify(double, |r: &f64| *r >= 0.0);
+    map_res(separated_pair(radius, char('@'), double), |(r, degrees)| {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let (x, y) = ((r * cos).round(), (r * sin).round());
+        // This also rules out NaN, e.g. from an angle of `nan`
+        let in_range = |n: f64| (0.0..=f64::from(u32::MAX)).contains(&n);
+        if !in_range(x) || !in_range(y) {
+            return Err(ErrorKind::MapRes);
+        }
+        Ok(Point {
+            x: x as u32,
+            y: y as u32,
+        })
+    })(input)
+}
+
+// Parse a list of points separated by spaces, e.g. the corners of a polygon
+pub fn parse_point_list(input: &str) -> IResult<&str, Vec<Point>> {
+    separated_list1(space1, Point::parse)(input)
+}
+
+// All points reachable from `start` by stepping up, down, left or right
+// through points that are in `grid`. Empty if `start` isn't in `grid` itself.
+pub fn flood_fill(grid: &HashSet<Point>, start: Point) -> HashSet<Point> {
+    let mut filled = HashSet::new();
+    let mut to_visit = vec![start];
+    while let Some(p) = to_visit.pop() {
+        if !grid.contains(&p) || !filled.insert(p) {
+            continue;
+        }
+        to_visit.extend(p.neighbors4());
+    }
+    filled
+}
+
+// A line spanning two points.
+// Lines compare (and hash) equal regardless of which end they start from, so
+// `0,9 -> 5,9` is the same line as `5,9 -> 0,9`.
+#[derive(Debug, Clone, Copy)]
 pub struct Line(pub Point, pub Point);
 
+impl Line {
+    // The endpoints as (x, y) pairs, smallest first
+    fn normalized(&self) -> ((u32, u32), (u32, u32)) {
+        let (a, b) = ((self.0.x, self.0.y), (self.1.x, self.1.y));
+        (a.min(b), a.max(b))
+    }
+}
+
+impl PartialEq for Line {
+    fn eq(&self, other: &Self) -> bool {
+        self.normalized() == other.normalized()
+    }
+}
+
+impl Eq for Line {}
+
+impl Hash for Line {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.normalized().hash(state);
+    }
+}
+
 impl Line {
     // Parse a line from the input string
     fn parse(input: &str) -> IResult<&str, Self> {
@@ -45,28 +347,510 @@ impl Line {
         // Parse two points separated by an arrow
         let parse_points = separated_pair(Point::parse, parse_arrow, Point::parse);
 
+        // Some inputs add a comment after the line, e.g. `0,9 -> 5,9 #color=red`.
+        // It's consumed but otherwise ignored.
+        let comment = preceded(pair(space0, char('#')), not_line_ending);
+
         // If the parse succeeded, put those two points into a Line
-        map(parse_points, |(p0, p1)| Line(p0, p1))(input)
+        map(terminated(parse_points, opt(comment)), |(p0, p1)| {
+            Line(p0, p1)
+        })(input)
+    }
+
+    // Parse the compact `x1,y1,x2,y2` format some datasets use instead of the arrow.
+    // It has to be exactly four numbers.
+    pub fn parse_flat(input: &str) -> IResult<&str, Self> {
+        let four_numbers = verify(
+            separated_list1(char(','), parse_numbers),
+            |numbers: &[u32]| numbers.len() == 4,
+        );
+        map(four_numbers, |n| {
+            Line(Point { x: n[0], y: n[1] }, Point { x: n[2], y: n[3] })
+        })(input)
+    }
+
+    // Parse a line that must be followed by a line ending or the end of the input,
+    // i.e. nothing else on the same line
+    fn parse_whole_line(input: &str) -> IResult<&str, Self> {
+        terminated(Self::parse, alt((line_ending, eof)))(input)
     }
 }
 
-// Parse the whole aoc day 5 file
-pub fn parse_input(s: &str) -> Vec<Line> {
-    let (remaining_input, lines) = separated_list1(line_ending, Line::parse)(s).unwrap();
+// A path through several points, e.g. `1,2 -> 3,4 -> 5,6`
+#[derive(Debug, Eq, PartialEq)]
+pub struct Path(pub Vec<Point>);
+
+impl Path {
+    // Parse two or more points separated by arrows
+    pub fn parse(input: &str) -> IResult<&str, Self> {
+        let parse_points = separated_list1(tag(" -> "), Point::parse);
+        map(
+            verify(parse_points, |points: &[Point]| points.len() >= 2),
+            Path,
+        )(input)
+    }
+
+    // Break the path into the line segments between consecutive points
+    pub fn lines(&self) -> Vec<Line> {
+        self.0.windows(2).map(|w| Line(w[0], w[1])).collect()
+    }
+}
+
+// Which way a line runs. Like the puzzle grid, y grows downwards, so a DiagDown
+// line goes down and to the right (e.g. 1,1 -> 3,3) and a DiagUp line goes up
+// and to the right (e.g. 1,3 -> 3,1). The order of the endpoints doesn't matter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Horizontal,
+    Vertical,
+    DiagUp,
+    DiagDown,
+}
+
+impl Line {
+    // None for lines that are neither horizontal, vertical nor at 45 degrees.
+    // A single point line counts as horizontal.
+    pub fn direction(&self) -> Option<Direction> {
+        let (axis, _, _) = self.axis_range()?;
+        let direction = match axis {
+            Axis::Horizontal(_) => Direction::Horizontal,
+            Axis::Vertical(_) => Direction::Vertical,
+            Axis::Diagonal(_) => Direction::DiagDown,
+            Axis::AntiDiagonal(_) => Direction::DiagUp,
+        };
+        Some(direction)
+    }
+
+    // Every grid point the line covers, from the first endpoint to the second,
+    // both included. Only horizontal, vertical and 45 degree lines cover whole
+    // grid points, so any other line gives an empty Vec.
+    pub fn points(&self) -> Vec<Point> {
+        if self.direction().is_none() {
+            return Vec::new();
+        }
+        let (Point { x: x0, y: y0 }, Point { x: x1, y: y1 }) = (self.0, self.1);
+        let (dx, dy) = (i64::from(x1) - i64::from(x0), i64::from(y1) - i64::from(y0));
+        let steps = dx.abs().max(dy.abs());
+        (0..=steps)
+            .map(|i| Point {
+                x: (i64::from(x0) + i * dx.signum()) as u32,
+                y: (i64::from(y0) + i * dy.signum()) as u32,
+            })
+            .collect()
+    }
+
+    // Add one to the count of every grid point the line covers. Each point is
+    // counted once per line, even for a zero length line whose endpoints are the
+    // same point.
+    pub fn rasterize_into(&self, map: &mut HashMap<Point, u32>) {
+        for point in self.points() {
+            *map.entry(point).or_insert(0) += 1;
+        }
+    }
+
+    // Cut the line in two at `p`: from the first endpoint to `p`, and from `p` to
+    // the second endpoint. None unless `p` is one of the line's grid points other
+    // than its endpoints.
+    pub fn split_at(&self, p: &Point) -> Option<(Line, Line)> {
+        if *p == self.0 || *p == self.1 || !self.points().contains(p) {
+            return None;
+        }
+        Some((Line(self.0, *p), Line(*p, self.1)))
+    }
+
+    // The straight-line distance between the endpoints. Unlike `points`, this
+    // works for lines at any angle.
+    pub fn length(&self) -> f64 {
+        // Go through i64 so that subtracting the larger coordinate can't underflow,
+        // and square in f64 since a u32 difference squared doesn't fit in an i64
+        let dx = i64::from(self.1.x) - i64::from(self.0.x);
+        let dy = i64::from(self.1.y) - i64::from(self.0.y);
+        (dx as f64).hypot(dy as f64)
+    }
+}
+
+// The infinite line a horizontal, vertical or 45 degree segment lies on.
+// Diagonals are identified by the constant x - y (going down-right) or x + y
+// (going up-right).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Axis {
+    Horizontal(u32),
+    Vertical(u32),
+    Diagonal(i64),
+    AntiDiagonal(i64),
+}
+
+impl Line {
+    // The axis this line lies on, and the range it covers along it (by x, or by y
+    // for vertical lines). None for lines that aren't axis-aligned or 45 degrees.
+    fn axis_range(&self) -> Option<(Axis, u32, u32)> {
+        let (Point { x: x0, y: y0 }, Point { x: x1, y: y1 }) = (self.0, self.1);
+        let (dx, dy) = (i64::from(x1) - i64::from(x0), i64::from(y1) - i64::from(y0));
+        let axis = if dy == 0 {
+            Axis::Horizontal(y0)
+        } else if dx == 0 {
+            return Some((Axis::Vertical(x0), y0.min(y1), y0.max(y1)));
+        } else if dx == dy {
+            Axis::Diagonal(i64::from(x0) - i64::from(y0))
+        } else if dx == -dy {
+            Axis::AntiDiagonal(i64::from(x0) + i64::from(y0))
+        } else {
+            return None;
+        };
+        Some((axis, x0.min(x1), x0.max(x1)))
+    }
+
+    // Rebuild a line from its axis and range, going from the low end to the high end
+    fn from_axis_range(axis: Axis, lo: u32, hi: u32) -> Line {
+        let point = |x: u32, y: i64| Point { x, y: y as u32 };
+        match axis {
+            Axis::Horizontal(y) => Line(Point { x: lo, y }, Point { x: hi, y }),
+            Axis::Vertical(x) => Line(Point { x, y: lo }, Point { x, y: hi }),
+            Axis::Diagonal(c) => Line(point(lo, i64::from(lo) - c), point(hi, i64::from(hi) - c)),
+            Axis::AntiDiagonal(c) => {
+                Line(point(lo, c - i64::from(lo)), point(hi, c - i64::from(hi)))
+            }
+        }
+    }
+}
+
+// Merge horizontal, vertical and 45 degree lines that lie on the same axis and
+// overlap or share an endpoint into single lines. Lines on the same axis with a
+// gap between them are kept apart, and any other lines are returned unchanged
+// after the merged ones. Merged lines always run from their low end to their
+// high end.
+pub fn merge_collinear(lines: &[Line]) -> Vec<Line> {
+    let mut by_axis: BTreeMap<Axis, Vec<(u32, u32)>> = BTreeMap::new();
+    let mut others = Vec::new();
+    for line in lines {
+        match line.axis_range() {
+            Some((axis, lo, hi)) => by_axis.entry(axis).or_default().push((lo, hi)),
+            None => others.push(*line),
+        }
+    }
+
+    let mut merged = Vec::new();
+    for (axis, mut ranges) in by_axis {
+        ranges.sort_unstable();
+        let mut current = ranges[0];
+        for (lo, hi) in ranges.into_iter().skip(1) {
+            if lo <= current.1 {
+                current.1 = current.1.max(hi);
+            } else {
+                merged.push(Line::from_axis_range(axis, current.0, current.1));
+                current = (lo, hi);
+            }
+        }
+        merged.push(Line::from_axis_range(axis, current.0, current.1));
+    }
+    merged.extend(others);
+    merged
+}
+
+// How many lines cover each grid point
+fn overlap_map(lines: &[Line]) -> HashMap<Point, u32> {
+    let mut counts = HashMap::new();
+    for line in lines {
+        line.rasterize_into(&mut counts);
+    }
+    counts
+}
+
+// The point covered by the most lines, and how many lines cover it.
+// If several points tie, any one of them is returned. None if no line covers
+// any point.
+pub fn max_overlap(lines: &[Line]) -> Option<(Point, u32)> {
+    overlap_map(lines)
+        .into_iter()
+        .max_by_key(|&(_, count)| count)
+}
+
+// Every point covered by at least two lines, in reading order (top to bottom,
+// then left to right), e.g. for highlighting them on a rendered grid
+pub fn overlapping_points(lines: &[Line]) -> Vec<Point> {
+    let mut points: Vec<Point> = overlap_map(lines)
+        .into_iter()
+        .filter(|&(_, count)| count >= 2)
+        .map(|(point, _)| point)
+        .collect();
+    points.sort_by_key(|p| (p.y, p.x));
+    points
+}
+
+// Keeps the number of points covered by at least two lines up to date as lines
+// are added one by one, instead of recounting everything for each new line
+#[derive(Debug, Default)]
+pub struct OverlapCounter {
+    counts: HashMap<Point, u32>,
+    overlaps: usize,
+}
+
+impl OverlapCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_line(&mut self, line: &Line) {
+        for point in line.points() {
+            let count = self.counts.entry(point).or_insert(0);
+            *count += 1;
+            // Only count a point the moment it becomes an overlap
+            if *count == 2 {
+                self.overlaps += 1;
+            }
+        }
+    }
+
+    // How many points are covered by at least two of the lines added so far
+    pub fn overlap_count(&self) -> usize {
+        self.overlaps
+    }
+}
+
+// The smallest and largest corners of the rectangle covering every line's
+// endpoints (inclusive), or None if there are no lines
+pub fn bounding_box(lines: &[Line]) -> Option<(Point, Point)> {
+    let mut points = lines.iter().flat_map(|line| [line.0, line.1]);
+    let first = points.next()?;
+    let corners = points.fold((first, first), |(min, max), p| {
+        (
+            Point {
+                x: min.x.min(p.x),
+                y: min.y.min(p.y),
+            },
+            Point {
+                x: max.x.max(p.x),
+                y: max.y.max(p.y),
+            },
+        )
+    });
+    Some(corners)
+}
+
+// Remove repeated lines (in either direction), keeping the first of each
+pub fn dedup_lines(lines: Vec<Line>) -> Vec<Line> {
+    let mut seen = HashSet::new();
+    lines
+        .into_iter()
+        .filter(|line| seen.insert(*line))
+        .collect()
+}
+
+// Parse the whole aoc day 5 file.
+// Files saved on Windows sometimes start with a byte order mark, which
+// `read_to_string` and `include_str!` keep, so skip it if it's there.
+pub fn parse_input(s: &str) -> IResult<&str, Vec<Line>> {
+    let byte_order_mark = char('\u{feff}');
+    preceded(
+        opt(byte_order_mark),
+        separated_list1(line_ending, Line::parse),
+    )(s)
+}
+
+// Parse the whole aoc day 5 file, panicking if it can't be parsed
+pub fn parse_input_unwrap(s: &str) -> Vec<Line> {
+    let (_remaining_input, lines) = parse_input(s).unwrap();
     //assert!(remaining_input.is_empty());
     lines
 }
 
+// Read and parse a gzipped input file. Both I/O and parse errors come back as an
+// `io::Error`, parse errors with `ErrorKind::InvalidData`.
+#[cfg(feature = "gzip")]
+pub fn parse_input_gz(path: &std::path::Path) -> std::io::Result<Vec<Line>> {
+    parse_gz(&std::fs::read(path)?)
+}
+
+#[cfg(feature = "gzip")]
+fn parse_gz(compressed: &[u8]) -> std::io::Result<Vec<Line>> {
+    use std::io::Read;
+
+    let mut text = String::new();
+    flate2::read::GzDecoder::new(compressed).read_to_string(&mut text)?;
+    let (_remaining_input, lines) = parse_input(&text).map_err(|e| {
+        let message = format!("Invalid input: {:?}", e.map(|e| e.code));
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    })?;
+    Ok(lines)
+}
+
+// Parse at most the first `n` lines, e.g. to preview a huge file, leaving the rest
+// of the input untouched. Stops early at the first line that doesn't parse.
+pub fn parse_first_n(s: &str, n: usize) -> IResult<&str, Vec<Line>> {
+    many_m_n(0, n, Line::parse_whole_line)(s)
+}
+
+// Parse lines up to the first blank line, for inputs with more than one section.
+// The blank line is consumed, and whatever follows it is left as the remaining input.
+pub fn parse_until_blank(s: &str) -> IResult<&str, Vec<Line>> {
+    map(
+        many_till(terminated(Line::parse, line_ending), line_ending),
+        |(lines, _blank)| lines,
+    )(s)
+}
+
+// Many puzzle inputs come in two sections separated by a blank line, e.g. a
+// block of data and then a block of instructions. Split the input at the first
+// blank line and run `first` over everything before it, which it has to use up
+// completely, then `second` over everything after it.
+pub fn parse_two_sections<'a, A, B, P1, P2>(
+    mut first: P1,
+    mut second: P2,
+) -> impl FnMut(&'a str) -> IResult<&'a str, (A, B)>
+where
+    P1: FnMut(&'a str) -> IResult<&'a str, A>,
+    P2: FnMut(&'a str) -> IResult<&'a str, B>,
+{
+    move |input| {
+        // A blank line is two line endings in a row, so Windows (`\r\n`) input works too
+        let blank_line = || pair(line_ending, line_ending);
+        let (rest, first_section) = terminated(
+            recognize(many_till(anychar, peek(blank_line()))),
+            blank_line(),
+        )(input)?;
+        let (_, a) = all_consuming(&mut first)(first_section)?;
+        let (rest, b) = second(rest)?;
+        Ok((rest, (a, b)))
+    }
+}
+
+// Parse lines one at a time, as they're asked for, instead of collecting them all
+// into a Vec. Stops after the first line that fails to parse, yielding its error.
+pub fn lines_iter<'a>(
+    s: &'a str,
+) -> impl Iterator<Item = Result<Line, nom::Err<Error<&'a str>>>> + 'a {
+    let mut input = s;
+    std::iter::from_fn(move || {
+        if input.is_empty() {
+            return None;
+        }
+        match Line::parse_whole_line(input) {
+            Ok((rest, line)) => {
+                input = rest;
+                Some(Ok(line))
+            }
+            Err(e) => {
+                input = "";
+                Some(Err(e))
+            }
+        }
+    })
+}
+
+// Like `parse_input`, but a line that fails to parse is skipped (up to the next
+// newline) instead of stopping the whole parse. Bad lines are silently dropped.
+pub fn parse_input_lenient(s: &str) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut input = s;
+    while !input.is_empty() {
+        match Line::parse_whole_line(input) {
+            Ok((rest, line)) => {
+                lines.push(line);
+                input = rest;
+            }
+            Err(_) => {
+                let skipped: IResult<&str, &str> = take_until("\n")(input);
+                input = match skipped {
+                    // Drop the newline too
+                    Ok((rest, _bad_line)) => &rest[1..],
+                    // The bad line was the last one
+                    Err(_) => "",
+                };
+            }
+        }
+    }
+    lines
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    // The example from the puzzle description
+    const EXAMPLE: &str = "0,9 -> 5,9
+8,0 -> 0,8
+9,4 -> 3,4
+2,2 -> 2,1
+7,0 -> 7,4
+6,4 -> 2,0
+0,9 -> 2,9
+3,4 -> 1,4
+0,0 -> 8,8
+5,5 -> 8,2";
+
     #[test]
     fn test_parse_numbers() {
         assert_eq!(Ok(("", 405)), parse_numbers("405"));
         assert_eq!(Ok(("abc", 405)), parse_numbers("405abc"));
     }
 
+    #[test]
+    fn test_parse_numbers_lenient() {
+        assert_eq!(Ok(("", 1000)), parse_numbers_lenient("1_000"));
+        assert_eq!(Ok((",2", 1_000_000)), parse_numbers_lenient("1_000_000,2"));
+        assert_eq!(Ok(("", 405)), parse_numbers_lenient("405"));
+        assert!(parse_numbers_lenient("_1").is_err());
+        // Only underscores with digits on both sides are part of the number
+        assert_eq!(Ok(("_,2", 1)), parse_numbers_lenient("1_,2"));
+        assert_eq!(Ok(("__0", 1)), parse_numbers_lenient("1__0"));
+        // The strict parser stops at the underscore
+        assert_eq!(Ok(("_000", 1)), parse_numbers("1_000"));
+        assert!(all_consuming(parse_numbers)("1_000").is_err());
+    }
+
+    #[test]
+    fn test_parse_numbers_fast() {
+        assert_eq!(Ok(("", 405)), parse_numbers_fast("405"));
+        assert_eq!(Ok(("abc", 405)), parse_numbers_fast("405abc"));
+        for input in ["", "abc", "4294967296"] {
+            assert_eq!(
+                parse_numbers(input).unwrap_err(),
+                parse_numbers_fast(input).unwrap_err()
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_number_auto() {
+        assert_eq!(parse_number_auto("0xFF"), Ok(("", 255)));
+        assert_eq!(parse_number_auto("0b1010"), Ok(("", 10)));
+        assert_eq!(parse_number_auto("0o17"), Ok(("", 15)));
+        assert_eq!(parse_number_auto("42"), Ok(("", 42)));
+        assert_eq!(parse_number_auto("0"), Ok(("", 0)));
+        assert_eq!(parse_number_auto("0,1"), Ok((",1", 0)));
+        // The digits have to suit the prefix
+        assert_eq!(parse_number_auto("0b12"), Ok(("2", 1)));
+        assert!(parse_number_auto("0b2").is_err());
+        assert!(parse_number_auto("0x").is_err());
+    }
+
+    #[test]
+    fn test_parse_pair() {
+        let mut parse_point =
+            parse_pair(parse_numbers, ',', parse_numbers, |(x, y)| Point { x, y });
+        assert_eq!(parse_point("3,4 ->"), Ok((" ->", Point { x: 3, y: 4 })));
+        assert!(parse_point("3;4").is_err());
+
+        // The two halves don't have to be the same type
+        let mut parse_named = parse_pair(
+            nom::character::complete::alpha1,
+            '=',
+            parse_numbers,
+            |pair| pair,
+        );
+        assert_eq!(parse_named("x=34"), Ok(("", ("x", 34))));
+    }
+
+    #[test]
+    fn test_parse_int_grid() {
+        let (rest, grid) = parse_int_grid("2 1 9\n3 9 8\n9 8 5\n").unwrap();
+        assert_eq!(rest, "\n");
+        assert_eq!(grid, vec![vec![2, 1, 9], vec![3, 9, 8], vec![9, 8, 5]]);
+
+        // A short row means it's not a grid
+        assert!(parse_int_grid("2 1 9\n3 9\n9 8 5").is_err());
+    }
+
     #[test]
     fn test_parse_point() {
         let tests = [
@@ -80,6 +864,200 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_point_strict() {
+        assert_eq!(Point::parse_strict("1,2"), Ok(("", Point { x: 1, y: 2 })));
+        assert!(Point::parse_strict("1,2asdf").is_err());
+        assert!(Point::parse_strict("1,2 ").is_err());
+    }
+
+    #[test]
+    fn test_parse_point_with_sep() {
+        let tests = [
+            (';', "3;4", Point { x: 3, y: 4 }, ""),
+            (' ', "3 4", Point { x: 3, y: 4 }, ""),
+            ('|', "3|4|5", Point { x: 3, y: 4 }, "|5"),
+        ];
+        for (sep, input, expected_output, expected_remaining_input) in tests {
+            let (remaining_input, output) = Point::parse_with_sep(sep)(input).unwrap();
+            assert_eq!(output, expected_output);
+            assert_eq!(remaining_input, expected_remaining_input);
+        }
+        assert!(Point::parse_with_sep(';')("3,4").is_err());
+    }
+
+    #[test]
+    fn test_parse_point_bytes() {
+        assert_eq!(
+            Point::parse_bytes(b"12,34"),
+            Ok((b"".as_ref(), Point { x: 12, y: 34 }))
+        );
+        assert_eq!(
+            Point::parse_bytes(b"1,2 -> 3,4"),
+            Ok((b" -> 3,4".as_ref(), Point { x: 1, y: 2 }))
+        );
+        // Too big for a u32
+        assert!(Point::parse_bytes(b"4294967296,0").is_err());
+        assert!(Point::parse_bytes(b"12;34").is_err());
+    }
+
+    #[test]
+    fn test_point_clamp() {
+        let inside = Point { x: 3, y: 4 };
+        assert_eq!(inside.clamp(10, 10), Point { x: 3, y: 4 });
+        let outside = Point { x: 30, y: 4 };
+        assert_eq!(outside.clamp(10, 2), Point { x: 10, y: 2 });
+    }
+
+    #[test]
+    fn test_point_in_rect() {
+        let (min, max) = (Point { x: 1, y: 1 }, Point { x: 4, y: 3 });
+        assert!(Point { x: 2, y: 2 }.in_rect(&min, &max));
+        assert!(Point { x: 4, y: 1 }.in_rect(&min, &max));
+        assert!(!Point { x: 5, y: 2 }.in_rect(&min, &max));
+        assert!(!Point { x: 2, y: 0 }.in_rect(&min, &max));
+        // A single cell rectangle
+        assert!(min.in_rect(&min, &min));
+        assert!(!max.in_rect(&min, &min));
+    }
+
+    #[test]
+    fn test_point_index() {
+        assert_eq!(Point { x: 2, y: 3 }.to_index(10), 32);
+        assert_eq!(Point::from_index(32, 10), Some(Point { x: 2, y: 3 }));
+        // A grid with no columns has no points
+        assert_eq!(Point::from_index(3, 0), None);
+        for width in [1, 7, 1000] {
+            for p in [
+                Point { x: 0, y: 0 },
+                Point { x: width - 1, y: 0 },
+                Point { x: 0, y: 5 },
+                Point {
+                    x: width / 2,
+                    y: 999,
+                },
+            ] {
+                assert_eq!(Point::from_index(p.to_index(width), width), Some(p));
+            }
+        }
+    }
+
+    #[test]
+    fn test_point_neighbors() {
+        let to_points = |coords: &[(u32, u32)]| -> Vec<Point> {
+            coords.iter().map(|&(x, y)| Point { x, y }).collect()
+        };
+        let corner = Point { x: 0, y: 0 };
+        assert_eq!(
+            corner.neighbors4().collect::<Vec<_>>(),
+            to_points(&[(1, 0), (0, 1)])
+        );
+        assert_eq!(
+            corner.neighbors8().collect::<Vec<_>>(),
+            to_points(&[(1, 0), (0, 1), (1, 1)])
+        );
+
+        let interior = Point { x: 5, y: 5 };
+        assert_eq!(
+            interior.neighbors4().collect::<Vec<_>>(),
+            to_points(&[(5, 4), (4, 5), (6, 5), (5, 6)])
+        );
+        assert_eq!(
+            interior.neighbors8().collect::<Vec<_>>(),
+            to_points(&[
+                (4, 4),
+                (5, 4),
+                (6, 4),
+                (4, 5),
+                (6, 5),
+                (4, 6),
+                (5, 6),
+                (6, 6)
+            ])
+        );
+    }
+
+    #[test]
+    fn test_point_add() {
+        let near_max = Point {
+            x: u32::MAX - 1,
+            y: 5,
+        };
+        let step = Point { x: 1, y: 1 };
+        let too_far = Point { x: 2, y: 1 };
+
+        assert_eq!(near_max + step, Point { x: u32::MAX, y: 6 });
+
+        assert_eq!(
+            near_max.checked_add(&step),
+            Some(Point { x: u32::MAX, y: 6 })
+        );
+        assert_eq!(near_max.checked_add(&too_far), None);
+
+        assert_eq!(near_max.saturating_add(&step), Point { x: u32::MAX, y: 6 });
+        assert_eq!(
+            near_max.saturating_add(&too_far),
+            Point { x: u32::MAX, y: 6 }
+        );
+    }
+
+    #[test]
+    fn test_delta() {
+        let (remaining_input, delta) = Delta::parse("+3,-2").unwrap();
+        assert_eq!(remaining_input, "");
+        assert_eq!(delta, Delta { dx: 3, dy: -2 });
+        assert_eq!(Delta::parse("3,2"), Ok(("", Delta { dx: 3, dy: 2 })));
+
+        let start = Point { x: 5, y: 5 };
+        assert_eq!(start.apply(&delta), Some(Point { x: 8, y: 3 }));
+        assert_eq!(start.apply(&Delta { dx: -6, dy: 0 }), None);
+        let edge = Point { x: u32::MAX, y: 0 };
+        assert_eq!(edge.apply(&Delta { dx: 1, dy: 0 }), None);
+    }
+
+    #[test]
+    fn test_parse_polar() {
+        assert_eq!(parse_polar("5@90"), Ok(("", Point { x: 0, y: 5 })));
+        assert_eq!(parse_polar("5@0 ->"), Ok((" ->", Point { x: 5, y: 0 })));
+        assert_eq!(parse_polar("5@45"), Ok(("", Point { x: 4, y: 4 })));
+        assert_eq!(parse_polar("2.5@36.87"), Ok(("", Point { x: 2, y: 2 })));
+        assert!(parse_polar("-5@90").is_err());
+        // Ends up at -5,0
+        assert!(parse_polar("5@180").is_err());
+        assert!(parse_polar("5@nan").is_err());
+    }
+
+    #[test]
+    fn test_parse_point_list() {
+        let (remaining_input, points) = parse_point_list("1,2 3,4 5,6").unwrap();
+        assert_eq!(
+            points,
+            vec![
+                Point { x: 1, y: 2 },
+                Point { x: 3, y: 4 },
+                Point { x: 5, y: 6 }
+            ]
+        );
+        assert_eq!(remaining_input, "");
+    }
+
+    #[test]
+    fn test_flood_fill() {
+        // Two regions: an L shape touching the origin, and a lone point at 5,5.
+        // 1,1 is diagonal to the L, which doesn't count as connected.
+        let (_, points) = parse_point_list("0,0 1,0 2,0 0,1 0,2 5,5 2,2").unwrap();
+        let grid: HashSet<Point> = points.into_iter().collect();
+
+        let (_, expected) = parse_point_list("0,0 1,0 2,0 0,1 0,2").unwrap();
+        let expected: HashSet<Point> = expected.into_iter().collect();
+        assert_eq!(flood_fill(&grid, Point { x: 0, y: 2 }), expected);
+
+        let lone = Point { x: 5, y: 5 };
+        assert_eq!(flood_fill(&grid, lone), HashSet::from([lone]));
+
+        assert!(flood_fill(&grid, Point { x: 1, y: 1 }).is_empty());
+    }
+
     #[test]
     fn test_parse_line() {
         let tests = [
@@ -100,11 +1078,356 @@ mod tests {
             assert_eq!(output, expected_output);
         }
     }
+    #[test]
+    fn test_parse_line_comment() {
+        let expected = Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 });
+        assert_eq!(
+            Line::parse("0,9 -> 5,9 #color=red\n8,0 -> 0,8"),
+            Ok(("\n8,0 -> 0,8", expected))
+        );
+        assert_eq!(Line::parse("0,9 -> 5,9#"), Ok(("", expected)));
+        let lines = parse_input_unwrap("0,9 -> 5,9 # first\n8,0 -> 0,8");
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_line_flat() {
+        assert_eq!(
+            Line::parse_flat("0,9,5,9"),
+            Ok(("", Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 })))
+        );
+        assert!(Line::parse_flat("0,9,5").is_err());
+        assert!(Line::parse_flat("0,9,5,9,1").is_err());
+    }
+
+    #[test]
+    fn test_parse_path() {
+        let (remaining_input, path) = Path::parse("1,2 -> 3,4 -> 5,6").unwrap();
+        assert_eq!(remaining_input, "");
+        assert_eq!(
+            path.lines(),
+            vec![
+                Line(Point { x: 1, y: 2 }, Point { x: 3, y: 4 }),
+                Line(Point { x: 3, y: 4 }, Point { x: 5, y: 6 }),
+            ]
+        );
+        // A single point isn't a path
+        assert!(Path::parse("1,2").is_err());
+    }
+
+    #[test]
+    fn test_line_direction() {
+        let tests = [
+            ("0,9 -> 5,9", Some(Direction::Horizontal)),
+            ("7,0 -> 7,4", Some(Direction::Vertical)),
+            ("1,1 -> 3,3", Some(Direction::DiagDown)),
+            ("3,3 -> 1,1", Some(Direction::DiagDown)),
+            ("1,3 -> 3,1", Some(Direction::DiagUp)),
+            ("3,1 -> 1,3", Some(Direction::DiagUp)),
+            ("0,0 -> 1,3", None),
+        ];
+        for (input, expected_direction) in tests {
+            let (_, line) = Line::parse(input).unwrap();
+            assert_eq!(line.direction(), expected_direction, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_line_points() {
+        let (_, line) = Line::parse("3,1 -> 1,3").unwrap();
+        assert_eq!(
+            line.points(),
+            vec![
+                Point { x: 3, y: 1 },
+                Point { x: 2, y: 2 },
+                Point { x: 1, y: 3 }
+            ]
+        );
+        let (_, line) = Line::parse("7,2 -> 7,0").unwrap();
+        assert_eq!(line.points().len(), 3);
+        let (_, line) = Line::parse("0,0 -> 1,3").unwrap();
+        assert!(line.points().is_empty());
+    }
+
+    #[test]
+    fn test_line_rasterize_into() {
+        let mut map = HashMap::new();
+        let (_, dot) = Line::parse("3,3 -> 3,3").unwrap();
+        assert_eq!(dot.points(), vec![Point { x: 3, y: 3 }]);
+        dot.rasterize_into(&mut map);
+        assert_eq!(map.len(), 1);
+        assert_eq!(map[&Point { x: 3, y: 3 }], 1);
+
+        // A second line through the same point counts it again
+        let (_, line) = Line::parse("1,1 -> 4,4").unwrap();
+        line.rasterize_into(&mut map);
+        assert_eq!(map.len(), 4);
+        assert_eq!(map[&Point { x: 3, y: 3 }], 2);
+    }
+
+    #[test]
+    fn test_line_split_at() {
+        let (_, line) = Line::parse("0,9 -> 5,9").unwrap();
+        let (first, second) = line.split_at(&Point { x: 2, y: 9 }).unwrap();
+        assert_eq!(first, Line(Point { x: 0, y: 9 }, Point { x: 2, y: 9 }));
+        assert_eq!(second, Line(Point { x: 2, y: 9 }, Point { x: 5, y: 9 }));
+
+        let (_, diagonal) = Line::parse("6,4 -> 2,0").unwrap();
+        let (first, second) = diagonal.split_at(&Point { x: 5, y: 3 }).unwrap();
+        assert_eq!(first, Line(Point { x: 6, y: 4 }, Point { x: 5, y: 3 }));
+        assert_eq!(second, Line(Point { x: 5, y: 3 }, Point { x: 2, y: 0 }));
+
+        // Off the line, or an endpoint
+        assert_eq!(line.split_at(&Point { x: 2, y: 8 }), None);
+        assert_eq!(line.split_at(&Point { x: 5, y: 9 }), None);
+        assert_eq!(diagonal.split_at(&Point { x: 4, y: 3 }), None);
+    }
+
+    #[test]
+    fn test_line_length() {
+        let (_, line) = Line::parse("5,9 -> 0,9").unwrap();
+        assert_eq!(line.length(), 5.0);
+        let (_, line) = Line::parse("7,0 -> 7,4").unwrap();
+        assert_eq!(line.length(), 4.0);
+        let (_, line) = Line::parse("0,0 -> 3,3").unwrap();
+        assert!((line.length() - 18f64.sqrt()).abs() < 1e-9);
+        // The squared difference would overflow an i64
+        let line = Line(Point { x: 0, y: 0 }, Point { x: u32::MAX, y: 0 });
+        assert_eq!(line.length(), f64::from(u32::MAX));
+        let line = Line(
+            Point {
+                x: u32::MAX,
+                y: u32::MAX,
+            },
+            Point { x: 0, y: 0 },
+        );
+        assert!((line.length() - f64::from(u32::MAX) * 2f64.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_max_overlap() {
+        // Three lines crossing at 2,2, two of them also share 1,2
+        let lines = parse_input_unwrap("0,2 -> 4,2\n2,0 -> 2,4\n0,0 -> 4,4\n1,2 -> 1,2");
+        assert_eq!(max_overlap(&lines), Some((Point { x: 2, y: 2 }, 3)));
+        assert_eq!(max_overlap(&[]), None);
+    }
+
+    #[test]
+    fn test_overlapping_points() {
+        let lines = parse_input_unwrap(EXAMPLE);
+        let expected: Vec<Point> = [
+            (7, 1),
+            (2, 2),
+            (5, 3),
+            (7, 3),
+            (3, 4),
+            (4, 4),
+            (6, 4),
+            (7, 4),
+            (5, 5),
+            (0, 9),
+            (1, 9),
+            (2, 9),
+        ]
+        .iter()
+        .map(|&(x, y)| Point { x, y })
+        .collect();
+        assert_eq!(overlapping_points(&lines), expected);
+        assert!(overlapping_points(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_overlap_counter() {
+        let mut counter = OverlapCounter::new();
+        assert_eq!(counter.overlap_count(), 0);
+        let lines = parse_input_unwrap("0,2 -> 4,2\n2,0 -> 2,4\n0,0 -> 4,4\n1,2 -> 3,2");
+        // The second line overlaps the first at 2,2. The diagonal only crosses
+        // them at 2,2 too, which is already counted. The last line adds 1,2 and 3,2.
+        let mut counts = Vec::new();
+        for line in &lines {
+            counter.add_line(line);
+            counts.push(counter.overlap_count());
+        }
+        assert_eq!(counts, vec![0, 1, 1, 3]);
+        assert_eq!(counter.overlap_count(), overlapping_points(&lines).len());
+    }
+
+    #[test]
+    fn test_merge_collinear() {
+        let lines = parse_input_unwrap("0,9 -> 5,9\n8,9 -> 3,9\n0,0 -> 2,0\n4,0 -> 6,0");
+        assert_eq!(
+            merge_collinear(&lines),
+            vec![
+                // Disjoint, so kept apart
+                Line(Point { x: 0, y: 0 }, Point { x: 2, y: 0 }),
+                Line(Point { x: 4, y: 0 }, Point { x: 6, y: 0 }),
+                // Overlapping, so merged
+                Line(Point { x: 0, y: 9 }, Point { x: 8, y: 9 }),
+            ]
+        );
+
+        // Touching diagonals merge, the odd slope line is left alone
+        let lines = parse_input_unwrap("0,0 -> 2,2\n4,4 -> 2,2\n6,0 -> 3,3\n0,0 -> 1,3");
+        assert_eq!(
+            merge_collinear(&lines),
+            vec![
+                Line(Point { x: 0, y: 0 }, Point { x: 4, y: 4 }),
+                Line(Point { x: 3, y: 3 }, Point { x: 6, y: 0 }),
+                Line(Point { x: 0, y: 0 }, Point { x: 1, y: 3 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_until_blank() {
+        let input = "0,9 -> 5,9\n8,0 -> 0,8\n\n7,4\n1,1 -> 2,2\n";
+        let (remaining_input, lines) = parse_until_blank(input).unwrap();
+        assert_eq!(
+            lines,
+            vec![
+                Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 }),
+                Line(Point { x: 8, y: 0 }, Point { x: 0, y: 8 }),
+            ]
+        );
+        assert_eq!(remaining_input, "7,4\n1,1 -> 2,2\n");
+        // No blank line to stop at
+        assert!(parse_until_blank("0,9 -> 5,9\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_two_sections() {
+        let points = separated_list1(line_ending, Point::parse);
+        let numbers = separated_list1(line_ending, parse_numbers);
+        let input = "1,2\n3,4\n\n5\n6\n";
+        let (remaining_input, (points, numbers)) =
+            parse_two_sections(points, numbers)(input).unwrap();
+        assert_eq!(points, vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+        assert_eq!(numbers, vec![5, 6]);
+        assert_eq!(remaining_input, "\n");
+
+        // No blank line, so there's no second section
+        let points = separated_list1(line_ending, Point::parse);
+        let numbers = separated_list1(line_ending, parse_numbers);
+        assert!(parse_two_sections(points, numbers)("1,2\n5").is_err());
+
+        // Windows line endings
+        let points = separated_list1(line_ending, Point::parse);
+        let numbers = separated_list1(line_ending, parse_numbers);
+        let (remaining_input, (points, numbers)) =
+            parse_two_sections(points, numbers)("1,2\r\n3,4\r\n\r\n5\r\n6").unwrap();
+        assert_eq!(points, vec![Point { x: 1, y: 2 }, Point { x: 3, y: 4 }]);
+        assert_eq!(numbers, vec![5, 6]);
+        assert_eq!(remaining_input, "");
+    }
+
+    #[test]
+    fn test_parse_first_n() {
+        let (remaining_input, lines) = parse_first_n(EXAMPLE, 3).unwrap();
+        assert_eq!(lines, parse_input_unwrap(EXAMPLE)[..3]);
+        assert!(remaining_input.starts_with("2,2 -> 2,1\n"));
+        // Asking for more lines than there are gives all of them
+        let (remaining_input, lines) = parse_first_n(EXAMPLE, 100).unwrap();
+        assert_eq!(lines.len(), 10);
+        assert_eq!(remaining_input, "");
+    }
+
+    #[test]
+    fn test_lines_iter() {
+        let input = "0,9 -> 5,9\n8,0 -> 0,8\n9,4 -> 3,4\nnot a line";
+        let first_two: Vec<Line> = lines_iter(input).take(2).map(Result::unwrap).collect();
+        assert_eq!(
+            first_two,
+            vec![
+                Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 }),
+                Line(Point { x: 8, y: 0 }, Point { x: 0, y: 8 }),
+            ]
+        );
+        let all: Vec<_> = lines_iter(input).collect();
+        assert_eq!(all.len(), 4);
+        assert!(all[3].is_err());
+    }
+
+    #[test]
+    fn test_parse_input_lenient() {
+        let input = "0,9 -> 5,9\n8,0 -> oops\n9,4 -> 3,4\n2,2 -> 2,1 trailing\n7,0 -> 7,4";
+        assert_eq!(
+            parse_input_lenient(input),
+            vec![
+                Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 }),
+                Line(Point { x: 9, y: 4 }, Point { x: 3, y: 4 }),
+                Line(Point { x: 7, y: 0 }, Point { x: 7, y: 4 }),
+            ]
+        );
+        // A bad last line, and Windows line endings
+        assert_eq!(
+            parse_input_lenient("0,9 -> 5,9\r\nbad"),
+            vec![Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 })]
+        );
+    }
+
+    #[test]
+    fn test_bounding_box() {
+        let lines = parse_input_unwrap(EXAMPLE);
+        assert_eq!(
+            bounding_box(&lines),
+            Some((Point { x: 0, y: 0 }, Point { x: 9, y: 9 }))
+        );
+        let lines = parse_input_unwrap("3,4 -> 1,4\n2,2 -> 2,6");
+        assert_eq!(
+            bounding_box(&lines),
+            Some((Point { x: 1, y: 2 }, Point { x: 3, y: 6 }))
+        );
+        assert_eq!(bounding_box(&[]), None);
+    }
+
+    #[test]
+    fn test_dedup_lines() {
+        let lines = parse_input_unwrap("0,9 -> 5,9\n8,0 -> 0,8\n5,9 -> 0,9\n0,9 -> 5,9");
+        assert_eq!(lines[0], lines[2]);
+        assert_eq!(
+            dedup_lines(lines),
+            vec![
+                Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 }),
+                Line(Point { x: 8, y: 0 }, Point { x: 0, y: 8 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let input = "0,9 -> 5,9\n8,0 -> 0,8";
+        let expected = vec![
+            Line(Point { x: 0, y: 9 }, Point { x: 5, y: 9 }),
+            Line(Point { x: 8, y: 0 }, Point { x: 0, y: 8 }),
+        ];
+        assert_eq!(parse_input(input), Ok(("", expected.clone())));
+        assert_eq!(parse_input_unwrap(input), expected);
+        assert!(parse_input("nonsense").is_err());
+        // A byte order mark at the start is skipped
+        let with_bom = format!("\u{feff}{}", input);
+        assert_eq!(parse_input(&with_bom), Ok(("", expected)));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_parse_gz() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(EXAMPLE.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(parse_gz(&compressed).unwrap(), parse_input_unwrap(EXAMPLE));
+
+        // Not gzipped at all
+        assert!(parse_gz(EXAMPLE.as_bytes()).is_err());
+        let path = std::path::Path::new("no/such/file.gz");
+        assert!(parse_input_gz(path).is_err());
+    }
+
     #[test]
     fn test_parse_file() {
-        let input =
-            include_str!("/Users/oepshtain/rust_projects/nom_stuff/text_with_nom/data/input.txt");
-        let lines = parse_input(input);
+        let input = include_str!("../data/input.txt");
+        let lines = parse_input_unwrap(input);
         assert_eq!(lines.len(), 500);
     }
 }